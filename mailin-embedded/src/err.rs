@@ -37,6 +37,20 @@ impl Error {
     {
         Err(Error::new(msg))
     }
+
+    // Did a read or write time out?
+    pub(crate) fn is_timeout(&self) -> bool {
+        self.original
+            .as_ref()
+            .and_then(|e| e.downcast_ref::<io::Error>())
+            .map(|e| {
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                )
+            })
+            .unwrap_or(false)
+    }
 }
 
 impl fmt::Display for Error {
@@ -43,8 +43,10 @@ mod ssl;
 use crate::err::Error;
 pub use crate::ssl::SslConfig;
 pub use mailin::response;
-pub use mailin::{Action, AuthMechanism, Handler, Response};
-use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+pub use mailin::{
+    Action, AuthMechanism, DisconnectReason, Handler, LinePolicy, Path, Response, SessionInfo,
+};
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
 
 /// `Server` is used to configure and start the SMTP server
 pub struct Server<H>
@@ -56,6 +58,14 @@ where
     ssl: Option<SslImpl>,
     num_threads: u32,
     auth: Vec<AuthMechanism>,
+    max_message_size: Option<usize>,
+    lmtp: bool,
+    line_policy: LinePolicy,
+    received_header: bool,
+    max_recipients: Option<usize>,
+    max_bad_commands: Option<usize>,
+    max_messages: Option<usize>,
+    trusted_peers: Vec<IpAddr>,
     tcp_listener: Option<TcpListener>,
     socket_address: Vec<SocketAddr>,
 }
@@ -72,6 +82,14 @@ where
             ssl: None,
             num_threads: 4,
             auth: Vec::with_capacity(4),
+            max_message_size: None,
+            lmtp: false,
+            line_policy: LinePolicy::default(),
+            received_header: false,
+            max_recipients: None,
+            max_bad_commands: None,
+            max_messages: None,
+            trusted_peers: Vec::new(),
             tcp_listener: None,
             socket_address: Vec::with_capacity(4),
         }
@@ -88,7 +106,21 @@ where
 
     /// Set the SSL configuration of the server
     pub fn with_ssl(&mut self, ssl_config: SslConfig) -> Result<&mut Self, Error> {
-        self.ssl = SslImpl::setup(ssl_config)?;
+        self.ssl = SslImpl::setup(ssl_config, None)?;
+        Ok(self)
+    }
+
+    /// Set the SSL configuration of the server and ask clients for a certificate
+    /// signed by one of the CAs in the given PEM file.
+    ///
+    /// Clients without a certificate can still connect. The certificates of verified
+    /// clients are used by `AuthMechanism::External`.
+    pub fn with_ssl_client_auth(
+        &mut self,
+        ssl_config: SslConfig,
+        client_ca_path: &str,
+    ) -> Result<&mut Self, Error> {
+        self.ssl = SslImpl::setup(ssl_config, Some(client_ca_path))?;
         Ok(self)
     }
 
@@ -105,6 +137,56 @@ where
         self
     }
 
+    /// Set the maximum size of messages that will be accepted by the server
+    pub fn with_max_message_size(&mut self, size: usize) -> &mut Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    /// Speak LMTP instead of SMTP
+    pub fn with_lmtp(&mut self) -> &mut Self {
+        self.lmtp = true;
+        self
+    }
+
+    /// Set how strictly lines sent by clients are checked, the default is
+    /// `LinePolicy::Normalize`. Lines longer than 16K are truncated unless the
+    /// policy is `LinePolicy::Lenient`.
+    pub fn with_line_policy(&mut self, policy: LinePolicy) -> &mut Self {
+        self.line_policy = policy;
+        self
+    }
+
+    /// Add a `Received:` trace header to incoming messages
+    pub fn with_received_header(&mut self) -> &mut Self {
+        self.received_header = true;
+        self
+    }
+
+    /// Set the maximum number of recipients in a mail transaction
+    pub fn with_max_recipients(&mut self, max: usize) -> &mut Self {
+        self.max_recipients = Some(max);
+        self
+    }
+
+    /// Set the maximum number of bad commands before a client is disconnected
+    pub fn with_max_bad_commands(&mut self, max: usize) -> &mut Self {
+        self.max_bad_commands = Some(max);
+        self
+    }
+
+    /// Set the maximum number of messages a client can send in one session
+    pub fn with_max_messages(&mut self, max: usize) -> &mut Self {
+        self.max_messages = Some(max);
+        self
+    }
+
+    /// Allow a trusted proxy at the given ip address to use XCLIENT and XFORWARD
+    pub fn with_trusted_peer(&mut self, ip: IpAddr) -> &mut Self {
+        self.trusted_peers.push(ip);
+        self
+    }
+
     /// Set a tcp listener from an already open socket
     pub fn with_tcp_listener(&mut self, listener: TcpListener) -> &mut Self {
         self.tcp_listener = Some(listener);
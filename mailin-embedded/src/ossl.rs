@@ -2,8 +2,8 @@ use crate::ssl::{SslConfig, Stream};
 use crate::Error;
 use openssl::error::ErrorStack;
 use openssl::pkey::PKey;
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslStream};
-use openssl::x509::X509;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::{X509Name, X509};
 use std::fmt::Display;
 use std::fs::File;
 use std::io::Read;
@@ -24,10 +24,33 @@ impl From<ErrorStack> for Error {
     }
 }
 
-impl Stream for SslStream<TcpStream> {}
+impl Stream for SslStream<TcpStream> {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        let ssl = self.ssl();
+        let leaf = match ssl.peer_certificate() {
+            Some(leaf) => leaf,
+            None => return Vec::new(),
+        };
+        // On the server side the peer chain does not include the client certificate
+        let chain = ssl.peer_cert_chain().into_iter().flatten();
+        std::iter::once(leaf.as_ref())
+            .chain(chain)
+            .filter_map(|cert| cert.to_der().ok())
+            .collect()
+    }
+
+    fn cipher(&self) -> Option<String> {
+        self.ssl()
+            .current_cipher()
+            .map(|cipher| cipher.name().to_string())
+    }
+}
 
 impl SslImpl {
-    pub fn setup(ssl_config: SslConfig) -> Result<Option<Self>, Error> {
+    pub fn setup(
+        ssl_config: SslConfig,
+        client_ca_path: Option<&str>,
+    ) -> Result<Option<Self>, Error> {
         let builder = match ssl_config {
             SslConfig::Trusted {
                 cert_path,
@@ -51,6 +74,13 @@ impl SslImpl {
             }
             _ => None,
         };
+        let builder = match (builder, client_ca_path) {
+            (Some(mut builder), Some(path)) => {
+                verify_clients(&mut builder, path)?;
+                Some(builder)
+            }
+            (builder, _) => builder,
+        };
         let ssl = builder.map(|b| SslImpl {
             acceptor: Arc::new(b.build()),
         });
@@ -78,6 +108,15 @@ fn ssl_builder(cert_path: String, key_path: String) -> Result<SslAcceptorBuilder
     Ok(builder)
 }
 
+// Verify client certificates against the CAs in the given file.
+// Clients that do not send a certificate are still accepted.
+fn verify_clients(builder: &mut SslAcceptorBuilder, ca_path: &str) -> Result<(), Error> {
+    builder.set_ca_file(ca_path)?;
+    builder.set_client_ca_list(X509Name::load_client_ca_file(ca_path)?);
+    builder.set_verify(SslVerifyMode::PEER);
+    Ok(())
+}
+
 pub fn slurp<P>(path: P) -> Result<Vec<u8>, Error>
 where
     P: AsRef<Path> + Display,
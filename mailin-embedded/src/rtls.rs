@@ -1,7 +1,9 @@
 use crate::ssl::{SslConfig, Stream};
 use crate::Error;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use rustls::{Error as TLSError, ServerConfig, ServerConnection, StreamOwned};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{Error as TLSError, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
 use std::fs;
 use std::io::BufReader;
 use std::net::TcpStream;
@@ -13,7 +15,20 @@ pub struct SslImpl {
     tls_config: Arc<ServerConfig>,
 }
 
-impl Stream for StreamOwned<ServerConnection, TcpStream> {}
+impl Stream for StreamOwned<ServerConnection, TcpStream> {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        self.conn
+            .peer_certificates()
+            .map(|certs| certs.iter().map(|c| c.as_ref().to_vec()).collect())
+            .unwrap_or_default()
+    }
+
+    fn cipher(&self) -> Option<String> {
+        self.conn
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+    }
+}
 
 impl From<TLSError> for Error {
     fn from(error: TLSError) -> Self {
@@ -23,7 +38,14 @@ impl From<TLSError> for Error {
 }
 
 impl SslImpl {
-    pub fn setup(ssl_config: SslConfig) -> Result<Option<Self>, Error> {
+    pub fn setup(
+        ssl_config: SslConfig,
+        client_ca_path: Option<&str>,
+    ) -> Result<Option<Self>, Error> {
+        let builder = match client_ca_path {
+            Some(path) => ServerConfig::builder().with_client_cert_verifier(client_verifier(path)?),
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
         let config = match ssl_config {
             SslConfig::Trusted {
                 cert_path,
@@ -34,9 +56,7 @@ impl SslImpl {
                 let mut chain = load_certs(&chain_path)?;
                 certs.append(&mut chain);
                 let key = load_key(&key_path)?;
-                let config = ServerConfig::builder()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, key)?;
+                let config = builder.with_single_cert(certs, key)?;
                 Some(config)
             }
             SslConfig::SelfSigned {
@@ -45,9 +65,7 @@ impl SslImpl {
             } => {
                 let certs = load_certs(&cert_path)?;
                 let key = load_key(&key_path)?;
-                let config = ServerConfig::builder()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, key)?;
+                let config = builder.with_single_cert(certs, key)?;
                 Some(config)
             }
             _ => None,
@@ -60,11 +78,28 @@ impl SslImpl {
 
     pub fn accept(&self, stream: TcpStream) -> Result<impl Stream, Error> {
         let session = ServerConnection::new(self.tls_config.clone())?;
-        let tls_stream = StreamOwned::new(session, stream);
+        let mut tls_stream = StreamOwned::new(session, stream);
+        // Complete the handshake so that the client certificate is available
+        while tls_stream.conn.is_handshaking() {
+            tls_stream.conn.complete_io(&mut tls_stream.sock)?;
+        }
         Ok(tls_stream)
     }
 }
 
+// Verify client certificates against the CAs in the given file.
+// Clients that do not send a certificate are still accepted.
+fn client_verifier(ca_path: &str) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(cert)?;
+    }
+    WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()
+        .map_err(|e| Error::with_source("Cannot verify client certificates", e))
+}
+
 fn load_certs(filename: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
     let certfile = fs::File::open(filename)?;
     let mut reader = BufReader::new(certfile);
@@ -10,16 +10,19 @@ use crate::ssl::Stream;
 use crate::Server;
 use bufstream_fresh::BufStream;
 use log::{debug, error, info};
-use mailin::{Action, Handler, Response, Session, SessionBuilder};
+use mailin::{Action, DisconnectReason, Handler, LinePolicy, Response, Session, SessionBuilder};
 use scoped_threadpool::Pool;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, Read, Write};
 use std::net::{IpAddr, TcpListener, TcpStream};
 use std::time::Duration;
 
 const FIVE_MINUTES: Duration = Duration::new(5 * 60, 0);
+// Longest incomplete line that will be buffered, longer lines are truncated unless
+// the line policy is lenient
+const MAX_LINE_LENGTH: u64 = 16 * 1024;
 
 enum SessionResult {
-    Finished,
+    Finished(DisconnectReason),
     UpgradeTls,
 }
 
@@ -30,6 +33,7 @@ where
     listener: TcpListener,
     handler: H,
     session_builder: SessionBuilder,
+    max_line_length: Option<u64>,
     ssl: Option<SslImpl>,
     num_threads: u32,
 }
@@ -45,6 +49,28 @@ where
     for auth in &config.auth {
         session_builder.enable_auth(auth.clone());
     }
+    if let Some(size) = config.max_message_size {
+        session_builder.max_message_size(size);
+    }
+    if config.lmtp {
+        session_builder.enable_lmtp();
+    }
+    session_builder.line_policy(config.line_policy);
+    if config.received_header {
+        session_builder.enable_received_header();
+    }
+    if let Some(max) = config.max_recipients {
+        session_builder.max_recipients(max);
+    }
+    if let Some(max) = config.max_bad_commands {
+        session_builder.max_bad_commands(max);
+    }
+    if let Some(max) = config.max_messages {
+        session_builder.max_messages(max);
+    }
+    for ip in &config.trusted_peers {
+        session_builder.trust_peer(*ip);
+    }
     let listen = if let Some(listener) = config.tcp_listener {
         listener
     } else {
@@ -56,6 +82,10 @@ where
         listener: listen,
         handler: config.handler,
         session_builder,
+        max_line_length: match config.line_policy {
+            LinePolicy::Lenient => None,
+            _ => Some(MAX_LINE_LENGTH),
+        },
         ssl: config.ssl,
         num_threads: config.num_threads,
     };
@@ -74,10 +104,11 @@ where
             match conn {
                 Ok(stream) => {
                     let builder = server_state.session_builder.clone();
+                    let max_line = server_state.max_line_length;
                     let acceptor = server_state.ssl.clone();
                     let handler_clone = server_state.handler.clone();
                     scoped.execute(move || {
-                        handle_connection(stream, &builder, acceptor, handler_clone)
+                        handle_connection(stream, &builder, max_line, acceptor, handler_clone)
                     });
                 }
                 Err(e) => error!("Connection failed: {}", e),
@@ -87,43 +118,92 @@ where
     Ok(())
 }
 
-fn handle_session<H, S>(session: &mut Session<H>, stream: &mut S) -> Result<SessionResult, Error>
+fn handle_session<H, S>(
+    session: &mut Session<H>,
+    stream: &mut S,
+    max_line: Option<u64>,
+) -> Result<SessionResult, Error>
 where
     S: BufRead + Write,
     H: Handler,
 {
     let mut line = Vec::with_capacity(80);
     loop {
-        line.clear();
-        let num_bytes = stream.read_until(b'\n', &mut line)?;
-        if num_bytes == 0 {
+        // Process the pipelined commands that have already been read
+        let buf = stream.fill_buf()?;
+        if buf.is_empty() {
             break;
         }
-        let res = session.process(&line);
-        match res.action {
-            Action::Reply => {
-                write_response(stream, &res)?;
+        let (consumed, responses) = session.process_buffer(buf);
+        let responses = if consumed > 0 {
+            stream.consume(consumed);
+            responses
+        } else {
+            // The buffer ends with an incomplete line
+            line.clear();
+            match max_line {
+                Some(max) => {
+                    Read::take(&mut *stream, max).read_until(b'\n', &mut line)?;
+                    if line.len() as u64 == max && !line.ends_with(b"\n") {
+                        // Drop the rest of the line, the session rejects the truncated line
+                        skip_line(stream)?;
+                        line.push(b'\n');
+                    }
+                }
+                None => {
+                    stream.read_until(b'\n', &mut line)?;
+                }
             }
-            Action::Close => {
-                write_response(stream, &res)?;
-                if res.is_error {
-                    return Error::bail("SMTP error");
-                } else {
-                    return Ok(SessionResult::Finished);
+            session.process_buffer(&line).1
+        };
+        // Responses are flushed at synchronisation points or when the buffer is exhausted
+        for res in &responses {
+            res.write_to(stream)?;
+        }
+        flush(stream)?;
+        if let Some(res) = responses.last() {
+            match res.action {
+                Action::Close => {
+                    let reason = if res.is_error {
+                        DisconnectReason::Closed
+                    } else {
+                        DisconnectReason::Quit
+                    };
+                    return Ok(SessionResult::Finished(reason));
                 }
+                Action::UpgradeTls => return Ok(SessionResult::UpgradeTls),
+                Action::Reply | Action::NoReply => (),
+            }
+        }
+    }
+    Ok(SessionResult::Finished(DisconnectReason::Eof))
+}
+
+fn skip_line<S: BufRead>(stream: &mut S) -> Result<(), Error> {
+    loop {
+        let buf = stream.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                stream.consume(pos + 1);
+                return Ok(());
             }
-            Action::UpgradeTls => {
-                write_response(stream, &res)?;
-                return Ok(SessionResult::UpgradeTls);
+            None => {
+                let len = buf.len();
+                stream.consume(len);
             }
-            Action::NoReply => (),
         }
     }
-    Error::bail("Unexpected Eof")
 }
 
 fn write_response(mut writer: &mut dyn Write, res: &Response) -> Result<(), Error> {
     res.write_to(&mut writer)?;
+    flush(writer)
+}
+
+fn flush(writer: &mut dyn Write) -> Result<(), Error> {
     writer
         .flush()
         .map_err(|e| Error::with_source("Cannot write response", e))
@@ -141,28 +221,58 @@ fn upgrade_tls(stream: TcpStream, ssl: Option<SslImpl>) -> Result<impl Stream, E
 fn start_session<H: Handler>(
     session_builder: &SessionBuilder,
     remote: IpAddr,
-    mut stream: BufStream<TcpStream>,
+    stream: BufStream<TcpStream>,
+    max_line: Option<u64>,
     ssl: Option<SslImpl>,
     handler: H,
 ) -> Result<(), Error> {
     let mut session = session_builder.build(remote, handler);
-    write_response(&mut stream, &session.greeting())?;
-    let res = handle_session(&mut session, &mut stream)?;
-    if let SessionResult::UpgradeTls = res {
-        let inner_stream = stream
-            .into_inner()
-            .map_err(|e| Error::with_source("Cannot flush original TcpStream", e))?;
-        let tls = upgrade_tls(inner_stream, ssl)?;
-        session.tls_active();
-        let mut buf_tls = BufStream::new(tls);
-        handle_session(&mut session, &mut buf_tls)?;
+    let res = run_session(&mut session, stream, max_line, ssl);
+    let reason = match res {
+        Ok(reason) => reason,
+        Err(ref err) if err.is_timeout() => DisconnectReason::Timeout,
+        Err(_) => DisconnectReason::Error,
+    };
+    debug!("({}) Disconnected: {:?}", remote, reason);
+    session.disconnect(reason);
+    res.map(|_| ())
+}
+
+fn run_session<H: Handler>(
+    session: &mut Session<H>,
+    mut stream: BufStream<TcpStream>,
+    max_line: Option<u64>,
+    ssl: Option<SslImpl>,
+) -> Result<DisconnectReason, Error> {
+    let greeting = session.greeting();
+    write_response(&mut stream, &greeting)?;
+    if greeting.action == Action::Close {
+        return Ok(DisconnectReason::Closed);
+    }
+    match handle_session(session, &mut stream, max_line)? {
+        SessionResult::Finished(reason) => Ok(reason),
+        SessionResult::UpgradeTls => {
+            let inner_stream = stream
+                .into_inner()
+                .map_err(|e| Error::with_source("Cannot flush original TcpStream", e))?;
+            let tls = upgrade_tls(inner_stream, ssl)?;
+            if let Some(cipher) = tls.cipher() {
+                session.set_tls_cipher(&cipher);
+            }
+            session.tls_active_with_peer_certificates(tls.peer_certificates());
+            let mut buf_tls = BufStream::new(tls);
+            match handle_session(session, &mut buf_tls, max_line)? {
+                SessionResult::Finished(reason) => Ok(reason),
+                SessionResult::UpgradeTls => Error::bail("TLS is already active"),
+            }
+        }
     }
-    Ok(())
 }
 
 fn handle_connection<H: Handler>(
     stream: TcpStream,
     session_builder: &SessionBuilder,
+    max_line: Option<u64>,
     ssl: Option<SslImpl>,
     handler: H,
 ) {
@@ -174,7 +284,7 @@ fn handle_connection<H: Handler>(
     stream.set_read_timeout(Some(FIVE_MINUTES)).ok();
     stream.set_write_timeout(Some(FIVE_MINUTES)).ok();
     let bufstream = BufStream::new(stream);
-    if let Err(err) = start_session(session_builder, remote, bufstream, ssl, handler) {
+    if let Err(err) = start_session(session_builder, remote, bufstream, max_line, ssl, handler) {
         debug!("({}) Cannot start session: {}", remote, err);
     }
 }
@@ -22,4 +22,11 @@ pub enum SslConfig {
     },
 }
 
-pub trait Stream: Read + Write {}
+pub trait Stream: Read + Write {
+    // The DER encoded certificate chain presented by the client, starting with
+    // the client certificate. Empty if the client did not send a certificate.
+    fn peer_certificates(&self) -> Vec<Vec<u8>>;
+
+    // The name of the negotiated cipher suite
+    fn cipher(&self) -> Option<String>;
+}
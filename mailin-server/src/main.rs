@@ -5,7 +5,7 @@ use anyhow::{anyhow, Context, Result};
 use getopts::Options;
 use log::error;
 use mailin_embedded::response::{BAD_HELLO, BLOCKED_IP, INTERNAL_ERROR, OK};
-use mailin_embedded::{Response, Server, SslConfig};
+use mailin_embedded::{DisconnectReason, Response, Server, SessionInfo, SslConfig};
 use mxdns::MxDns;
 use simplelog::{
     ColorChoice, CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode, WriteLogger,
@@ -14,7 +14,7 @@ use std::env;
 use std::fs::File;
 use std::io;
 use std::io::Write;
-use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::net::{Ipv4Addr, TcpListener};
 use std::path::Path;
 use time::{format_description, OffsetDateTime};
 
@@ -31,6 +31,7 @@ const OPT_SSL_KEY: &str = "ssl-key";
 const OPT_SSL_CHAIN: &str = "ssl-chain";
 const OPT_BLOCKLIST: &str = "blocklist";
 const OPT_MAILDIR: &str = "maildir";
+const OPT_RECEIVED_HEADER: &str = "received-header";
 
 #[derive(Clone)]
 struct Handler<'a> {
@@ -39,7 +40,8 @@ struct Handler<'a> {
 }
 
 impl<'a> mailin_embedded::Handler for Handler<'a> {
-    fn helo(&mut self, ip: IpAddr, _domain: &str) -> Response {
+    fn helo(&mut self, info: &SessionInfo, _domain: &str) -> Response {
+        let ip = info.ip();
         if ip == Ipv4Addr::new(127, 0, 0, 1) {
             return OK;
         }
@@ -59,10 +61,12 @@ impl<'a> mailin_embedded::Handler for Handler<'a> {
 
     fn data_start(
         &mut self,
-        _domain: &str,
-        _from: &str,
+        _info: &SessionInfo,
+        _from: &mailin_embedded::Path,
         _is8bit: bool,
-        _to: &[String],
+        _smtputf8: bool,
+        _require_tls: bool,
+        _to: &[mailin_embedded::Path],
     ) -> Response {
         match self.mailstore.start_message() {
             Ok(()) => OK,
@@ -73,11 +77,11 @@ impl<'a> mailin_embedded::Handler for Handler<'a> {
         }
     }
 
-    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+    fn data(&mut self, _info: &SessionInfo, buf: &[u8]) -> io::Result<()> {
         self.mailstore.write_all(buf)
     }
 
-    fn data_end(&mut self) -> Response {
+    fn data_end(&mut self, _info: &SessionInfo) -> Response {
         match self.mailstore.end_message() {
             Ok(()) => OK,
             Err(err) => {
@@ -86,6 +90,22 @@ impl<'a> mailin_embedded::Handler for Handler<'a> {
             }
         }
     }
+
+    fn rset(&mut self, _info: &SessionInfo) {
+        self.abort_message();
+    }
+
+    fn disconnect(&mut self, _info: &SessionInfo, _reason: DisconnectReason) {
+        self.abort_message();
+    }
+}
+
+impl<'a> Handler<'a> {
+    fn abort_message(&mut self) {
+        if let Err(err) = self.mailstore.abort_message() {
+            error!("Abort message: {}", err);
+        }
+    }
 }
 
 fn setup_logger(log_dir: Option<String>) -> Result<()> {
@@ -145,6 +165,11 @@ fn main() -> Result<()> {
         "PEM_FILE",
     );
     opts.optopt("", OPT_MAILDIR, "the directory to store mail in", "MAILDIR");
+    opts.optflag(
+        "",
+        OPT_RECEIVED_HEADER,
+        "add a Received: header to incoming mail",
+    );
     let matches = opts
         .parse(&args[1..])
         .context("Cannot parse command line")?;
@@ -176,6 +201,9 @@ fn main() -> Result<()> {
         .with_name(domain)
         .with_ssl(ssl_config)
         .map_err(|e| anyhow!("Cannot initialise SSL: {}", e))?;
+    if matches.opt_present(OPT_RECEIVED_HEADER) {
+        server.with_received_header();
+    }
     // Bind TCP listener
     let addr = matches
         .opt_str(OPT_ADDRESS)
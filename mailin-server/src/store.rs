@@ -45,6 +45,8 @@ impl MailStore {
     }
 
     pub fn start_message(&mut self) -> io::Result<()> {
+        // A previous message may have been rejected before the end of data
+        self.abort_message()?;
         let mut path = self.dir.clone();
         path.push("tmp");
         fs::create_dir_all(&path)?;
@@ -71,6 +73,18 @@ impl MailStore {
             .unwrap_or(Ok(()))
     }
 
+    // Remove the partly written message, if any
+    pub fn abort_message(&mut self) -> io::Result<()> {
+        self.state
+            .take()
+            .map(|state| {
+                info!("Removing aborted message {:#?}", state.path);
+                drop(state.parser);
+                fs::remove_file(&state.path)
+            })
+            .unwrap_or(Ok(()))
+    }
+
     fn message_file(&self) -> String {
         let mut filename = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
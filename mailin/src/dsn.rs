@@ -0,0 +1,55 @@
+/// The DSN parameters given with a MAIL command
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MailDsn {
+    /// How much of the message to return in a failure notification, `RET=`
+    pub ret: Option<Ret>,
+    /// The envelope identifier given by the client, `ENVID=`, decoded from xtext
+    pub envid: Option<String>,
+}
+
+/// The amount of a message to return in a delivery status notification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ret {
+    /// Return the full message, `RET=FULL`
+    Full,
+    /// Only return the message headers, `RET=HDRS`
+    Headers,
+}
+
+/// The DSN parameters given with a RCPT command
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RcptDsn {
+    /// The conditions that should trigger a notification, `NOTIFY=`
+    pub notify: Option<Notify>,
+    /// The original recipient, `ORCPT=`
+    pub orcpt: Option<OriginalRecipient>,
+}
+
+/// The conditions under which a delivery status notification should be sent.
+///
+/// `NOTIFY=NEVER` is represented with all the conditions unset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Notify {
+    /// Notify on successful delivery
+    pub success: bool,
+    /// Notify on failed delivery
+    pub failure: bool,
+    /// Notify when delivery is delayed
+    pub delay: bool,
+}
+
+impl Notify {
+    /// Should notifications never be sent?
+    pub fn is_never(&self) -> bool {
+        !(self.success || self.failure || self.delay)
+    }
+}
+
+/// The original recipient of a message given in the `ORCPT=` parameter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OriginalRecipient {
+    /// The type of address, usually `rfc822`
+    pub addr_type: String,
+    /// The address, decoded from xtext
+    pub address: String,
+}
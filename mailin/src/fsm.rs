@@ -1,12 +1,26 @@
-use crate::parser::{decode_sasl_login, decode_sasl_plain, parse, parse_auth_response};
+use crate::parser::{
+    decode_cram_md5, decode_oauthbearer, decode_sasl_external, decode_sasl_login,
+    decode_sasl_plain, decode_scram_client_final, decode_scram_client_first, decode_xoauth2, parse,
+    parse_auth_response, MAIL_KEYWORDS, RCPT_KEYWORDS,
+};
+use crate::path::Path;
+use crate::received::received_header;
 use crate::response::*;
+use crate::sasl::{
+    cram_md5_challenge, cram_md5_verify, scram_nonce, scram_unknown_user, ScramServer, BEARER_ERROR,
+};
 
-use crate::smtp::Cmd;
-use crate::{AuthMechanism, Handler, Response};
+use crate::smtp::{BodyType, Cmd};
+use crate::{
+    AuthMechanism, DisconnectReason, EsmtpParams, Event, Handler, LinePolicy, Response,
+    SaslExchange, SaslMechanism, SaslStep, SessionInfo,
+};
 use either::*;
 use log::{error, trace};
-use std::borrow::BorrowMut;
-use std::net::IpAddr;
+use std::borrow::{BorrowMut, Cow};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::SystemTime;
 use ternop::ternary;
 
 #[cfg(test)]
@@ -20,6 +34,24 @@ pub(crate) enum SmtpState {
     Mail,
     Rcpt,
     Data,
+    Bdat,
+    BdatDiscard,
+}
+
+// The longest lines allowed, including the CRLF (RFC 5321 section 4.5.3.1, RFC 4954 section 4)
+const MAX_COMMAND_LINE: usize = 512;
+const MAX_AUTH_LINE: usize = 12288;
+const MAX_TEXT_LINE: usize = 1000;
+
+// The input that a state reads from the client
+#[derive(PartialEq)]
+enum Input {
+    Command,
+    AuthResponse,
+    // Lines of a message sent with DATA
+    Text,
+    // Binary data sent with BDAT
+    Chunk,
 }
 
 #[derive(PartialEq)]
@@ -35,6 +67,25 @@ enum AuthState {
     Authenticated,
 }
 
+// How a decision that the Handler would make is taken
+enum Decision {
+    // Ask the handler
+    Ask,
+    // The decision was deferred and has since been made, with one response for each
+    // recipient if it is an LMTP end of data decision
+    Made(Vec<Response>),
+    // The decision is deferred, the session waits until it is made
+    Pending,
+}
+
+// A command waiting for a deferred decision, which is handled again once the decision
+// has been made
+enum Parked {
+    Command(Vec<u8>),
+    DataEnd,
+    ChunkEnd,
+}
+
 trait State: Send + Sync {
     #[cfg(test)]
     fn id(&self) -> SmtpState;
@@ -52,12 +103,22 @@ trait State: Send + Sync {
     // override this method.
     fn process_line<'a>(
         &mut self,
+        _info: &SessionInfo,
         _handler: &mut dyn Handler,
         line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
         trace!("> {}", String::from_utf8_lossy(line));
         parse(line).map(Left).unwrap_or_else(Right)
     }
+
+    // The number of bytes of binary BDAT data the state is waiting for
+    fn chunk_remaining(&self) -> usize {
+        0
+    }
+
+    fn input(&self) -> Input {
+        Input::Command
+    }
 }
 
 //------------------------------------------------------------------------------
@@ -99,26 +160,198 @@ where
     }
 }
 
+// Keep the current state until a deferred decision has been made
+fn pending(current: Box<dyn State>) -> (Response, Option<Box<dyn State>>) {
+    (EMPTY_RESPONSE, Some(current))
+}
+
 fn default_handler(
     current: Box<dyn State>,
-    fsm: &StateMachine,
+    fsm: &mut StateMachine,
     handler: &mut dyn Handler,
     cmd: &Cmd,
 ) -> (Response, Option<Box<dyn State>>) {
     match *cmd {
-        Cmd::Quit => (GOODBYE, None),
+        Cmd::Quit => {
+            handler.quit(&fsm.info);
+            (GOODBYE, None)
+        }
+        // LMTP clients must use LHLO
+        Cmd::Helo { .. } | Cmd::Ehlo { .. } if fsm.lmtp => (SYNTAX_ERROR, Some(current)),
+        Cmd::Lhlo { .. } if !fsm.lmtp => (SYNTAX_ERROR, Some(current)),
         Cmd::Helo { domain } => handle_helo(current, fsm, handler, domain),
-        Cmd::Ehlo { domain } => handle_ehlo(current, fsm, handler, domain),
+        Cmd::Ehlo { domain } | Cmd::Lhlo { domain } => handle_ehlo(current, fsm, handler, domain),
         Cmd::Noop => (OK, Some(current)),
+        Cmd::Unknown { verb, args } => {
+            let res = handler.unknown_command(&fsm.info, verb, args);
+            (res, Some(current))
+        }
+        // The chunk data is sent regardless and must be read before responding
+        Cmd::Bdat { size, .. } => discard_chunk(current, size, BAD_SEQUENCE_COMMANDS),
         _ => unhandled(current),
     }
 }
 
+// Read and drop a BDAT chunk before sending the given response
+fn discard_chunk(
+    current: Box<dyn State>,
+    size: usize,
+    res: Response,
+) -> (Response, Option<Box<dyn State>>) {
+    if size == 0 {
+        (res, Some(current))
+    } else {
+        (
+            EMPTY_RESPONSE,
+            Some(Box::new(BdatDiscard {
+                remaining: size,
+                response: res,
+                previous: current,
+            })),
+        )
+    }
+}
+
 fn unhandled(current: Box<dyn State>) -> (Response, Option<Box<dyn State>>) {
     (BAD_SEQUENCE_COMMANDS, Some(current))
 }
 
-fn handle_rset(fsm: &StateMachine, domain: &str) -> (Response, Option<Box<dyn State>>) {
+// Finish receiving a message and return the response from the handler, or None if
+// the decision has been deferred. In LMTP there is a response for each recipient.
+fn end_of_data(fsm: &mut StateMachine, handler: &mut dyn Handler, to: &[Path]) -> Option<Response> {
+    match fsm.decision(|| Event::NeedDataEndDecision { to: to.to_vec() }) {
+        Decision::Ask if fsm.lmtp => Some(lmtp_responses(handler.data_end_lmtp(&fsm.info, to), to)),
+        Decision::Ask => Some(handler.data_end(&fsm.info)),
+        Decision::Made(responses) if fsm.lmtp && responses.len() != 1 => {
+            Some(lmtp_responses(responses, to))
+        }
+        Decision::Made(responses) => Some(data_failed(fsm, first_response(responses), to)),
+        Decision::Pending => None,
+    }
+}
+
+// The responses to the end of data in LMTP, one for each recipient. Missing responses
+// repeat the last one, or are a temporary failure if there are none, so that the client
+// is not left waiting.
+fn lmtp_responses(mut responses: Vec<Response>, to: &[Path]) -> Response {
+    if responses.len() != to.len() {
+        error!(
+            "{} LMTP responses given for {} recipients",
+            responses.len(),
+            to.len()
+        );
+        let last = responses.last().cloned().unwrap_or(INTERNAL_ERROR);
+        responses.resize(to.len(), last);
+    }
+    Response::multiple(responses)
+}
+
+// The response to a decision that was made with a single response
+fn first_response(responses: Vec<Response>) -> Response {
+    responses.into_iter().next().unwrap_or(INTERNAL_ERROR)
+}
+
+// A response to the end of data that does not come from the handler.
+// Single deferred decisions are also given this way.
+// In LMTP the response is repeated for each recipient.
+fn data_failed(fsm: &StateMachine, res: Response, to: &[Path]) -> Response {
+    if fsm.lmtp {
+        Response::multiple(vec![res; to.len()])
+    } else {
+        res
+    }
+}
+
+// Is the character at the given index a CR or LF that is not part of a CRLF?
+fn is_bare_line_ending(line: &[u8], i: usize) -> bool {
+    match line[i] {
+        b'\r' => line.get(i + 1) != Some(&b'\n'),
+        b'\n' => i == 0 || line[i - 1] != b'\r',
+        _ => false,
+    }
+}
+
+// Does the line contain a CR or LF that is not part of a CRLF?
+fn has_bare_line_ending(line: &[u8]) -> bool {
+    (0..line.len()).any(|i| is_bare_line_ending(line, i))
+}
+
+// Does the line end with a CR or LF that is not part of a CRLF?
+fn ends_with_bare_line_ending(line: &[u8]) -> bool {
+    !line.is_empty() && is_bare_line_ending(line, line.len() - 1)
+}
+
+// Is a bare CR or LF followed by a dot? Once normalized this would hide a dot line,
+// possibly the end of data, inside another line, e.g. "foo\r.\r\n"
+fn has_dot_after_bare_line_ending(line: &[u8]) -> bool {
+    (0..line.len()).any(|i| line.get(i + 1) == Some(&b'.') && is_bare_line_ending(line, i))
+}
+
+// Convert bare CR and LF to CRLF
+fn normalize_line_endings(line: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(line.len() + 2);
+    let mut it = line.iter().peekable();
+    while let Some(c) = it.next() {
+        match c {
+            b'\r' if it.peek() == Some(&&b'\n') => (),
+            b'\r' | b'\n' => normalized.extend_from_slice(b"\r\n"),
+            _ => normalized.push(*c),
+        }
+    }
+    normalized
+}
+
+// Check a line of a message against the line policy
+fn check_text_line(policy: LinePolicy, line: &[u8]) -> Result<Cow<'_, [u8]>, Response> {
+    if policy == LinePolicy::Lenient {
+        Ok(Cow::Borrowed(line))
+    } else if line.len() > MAX_TEXT_LINE {
+        Err(MESSAGE_LINE_TOO_LONG)
+    } else if !has_bare_line_ending(line) {
+        Ok(Cow::Borrowed(line))
+    } else if policy == LinePolicy::Normalize && !has_dot_after_bare_line_ending(line) {
+        Ok(Cow::Owned(normalize_line_endings(line)))
+    } else {
+        Err(MESSAGE_BARE_LINE_ENDING)
+    }
+}
+
+// The transaction is complete, whatever the response
+fn end_transaction(
+    fsm: &mut StateMachine,
+    res: Response,
+    domain: String,
+) -> (Response, Option<Box<dyn State>>) {
+    fsm.info.end_transaction();
+    if !res.is_error {
+        fsm.messages += 1;
+    }
+    if res.action == Action::Close {
+        (res, None)
+    } else {
+        (res, Some(Box::new(Hello { domain })))
+    }
+}
+
+// The server ends a transaction that did not end with a successful end of data, e.g.
+// a message that is too large, the handler is told as for RSET
+fn abort_transaction(
+    fsm: &mut StateMachine,
+    handler: &mut dyn Handler,
+    res: Response,
+    domain: String,
+) -> (Response, Option<Box<dyn State>>) {
+    handler.rset(&fsm.info);
+    end_transaction(fsm, res, domain)
+}
+
+fn handle_rset(
+    fsm: &mut StateMachine,
+    handler: &mut dyn Handler,
+    domain: &str,
+) -> (Response, Option<Box<dyn State>>) {
+    handler.rset(&fsm.info);
+    fsm.info.end_transaction();
     match fsm.auth_state {
         AuthState::Unavailable => (
             OK,
@@ -135,15 +368,136 @@ fn handle_rset(fsm: &StateMachine, domain: &str) -> (Response, Option<Box<dyn St
     }
 }
 
+// The value of an XCLIENT or XFORWARD attribute, which the proxy can mark as unavailable
+fn proxy_value(value: &str) -> Option<&str> {
+    ternary!(
+        value == "[UNAVAILABLE]" || value == "[TEMPUNAVAIL]",
+        None,
+        Some(value)
+    )
+}
+
+// Parse an ADDR attribute, IPv6 addresses start with "IPV6:"
+fn proxy_addr(value: &str) -> Option<IpAddr> {
+    match value.get(..5) {
+        Some(tag) if tag.eq_ignore_ascii_case("ipv6:") => {
+            value[5..].parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+        }
+        _ => value.parse().ok(),
+    }
+}
+
+// XCLIENT from a proxy overrides the client details and restarts the session (Postfix)
+fn handle_xclient(
+    current: Box<dyn State>,
+    fsm: &mut StateMachine,
+    handler: &mut dyn Handler,
+    attributes: &[(&str, String)],
+) -> (Response, Option<Box<dyn State>>) {
+    if !fsm.trusted_peer {
+        return (PROXY_NOT_AUTHORIZED, Some(current));
+    }
+    // Nothing changes if any attribute is bad
+    let mut info = fsm.info.clone();
+    let mut login = None;
+    for (name, value) in attributes {
+        // Decoded values must not smuggle control characters into headers
+        if value.bytes().any(|c| c.is_ascii_control()) {
+            return (BAD_PROXY_ATTRIBUTE, Some(current));
+        }
+        let value = proxy_value(value);
+        match name.to_ascii_uppercase().as_str() {
+            "NAME" => info.set_client_name(value.map(str::to_string)),
+            "ADDR" => match value.map(proxy_addr) {
+                Some(Some(ip)) => info.set_ip(ip),
+                Some(None) => return (BAD_PROXY_ATTRIBUTE, Some(current)),
+                None => (),
+            },
+            "HELO" => info.set_helo(value.map(str::to_string)),
+            "LOGIN" => login = Some(value.map(str::to_string)),
+            _ => return (BAD_PROXY_ATTRIBUTE, Some(current)),
+        }
+    }
+    if let Some(identity) = login {
+        if !matches!(fsm.auth_state, AuthState::Unavailable) {
+            fsm.auth_state = ternary!(
+                identity.is_some(),
+                AuthState::Authenticated,
+                AuthState::RequiresAuth
+            );
+        }
+        info.set_auth_identity(identity);
+    }
+    // The session restarts as if the client sent RSET
+    handler.rset(&fsm.info);
+    fsm.info = info;
+    fsm.info.end_transaction();
+    // The client must say EHLO again
+    fsm.ehlo = false;
+    fsm.enhanced_status_codes = false;
+    (fsm.greeting(), Some(Box::new(Idle {})))
+}
+
+// XFORWARD from a proxy MTA gives the original client of the next mail transaction
+fn handle_xforward(
+    current: Box<dyn State>,
+    fsm: &mut StateMachine,
+    attributes: &[(&str, String)],
+) -> (Response, Option<Box<dyn State>>) {
+    if !fsm.trusted_peer {
+        return (PROXY_NOT_AUTHORIZED, Some(current));
+    }
+    // Nothing changes if any attribute is bad
+    let mut forwarded = fsm.info.forwarded().cloned().unwrap_or_default();
+    for (name, value) in attributes {
+        // Decoded values must not smuggle control characters into headers
+        if value.bytes().any(|c| c.is_ascii_control()) {
+            return (BAD_PROXY_ATTRIBUTE, Some(current));
+        }
+        let value = proxy_value(value);
+        match name.to_ascii_uppercase().as_str() {
+            "NAME" => forwarded.name = value.map(str::to_string),
+            "ADDR" => match value.map(proxy_addr) {
+                Some(None) => return (BAD_PROXY_ATTRIBUTE, Some(current)),
+                addr => forwarded.addr = addr.flatten(),
+            },
+            "PORT" => match value.map(str::parse) {
+                Some(Err(_)) => return (BAD_PROXY_ATTRIBUTE, Some(current)),
+                port => forwarded.port = port.and_then(Result::ok),
+            },
+            "PROTO" => forwarded.proto = value.map(str::to_string),
+            "HELO" => forwarded.helo = value.map(str::to_string),
+            "IDENT" => forwarded.ident = value.map(str::to_string),
+            "SOURCE" => forwarded.source = value.map(str::to_string),
+            _ => return (BAD_PROXY_ATTRIBUTE, Some(current)),
+        }
+    }
+    fsm.info.set_forwarded(forwarded);
+    (OK, Some(current))
+}
+
 fn handle_helo(
     current: Box<dyn State>,
-    fsm: &StateMachine,
+    fsm: &mut StateMachine,
     handler: &mut dyn Handler,
     domain: &str,
 ) -> (Response, Option<Box<dyn State>>) {
     match fsm.auth_state {
         AuthState::Unavailable => {
-            let res = handler.helo(fsm.ip, domain);
+            let res = match fsm.decide(
+                || Event::NeedHeloDecision {
+                    domain: domain.to_string(),
+                },
+                |info| handler.helo(info, domain),
+            ) {
+                Some(res) => res,
+                None => return pending(current),
+            };
+            if !res.is_error {
+                fsm.ehlo = false;
+                fsm.enhanced_status_codes = false;
+                fsm.info.set_helo_domain(domain);
+            }
             next_state(current, res, || {
                 Box::new(Hello {
                     domain: domain.to_owned(),
@@ -159,13 +513,25 @@ fn handle_helo(
 
 fn handle_ehlo(
     current: Box<dyn State>,
-    fsm: &StateMachine,
+    fsm: &mut StateMachine,
     handler: &mut dyn Handler,
     domain: &str,
 ) -> (Response, Option<Box<dyn State>>) {
-    let mut res = handler.helo(fsm.ip, domain);
+    let mut res = match fsm.decide(
+        || Event::NeedHeloDecision {
+            domain: domain.to_string(),
+        },
+        |info| handler.helo(info, domain),
+    ) {
+        Some(res) => res,
+        None => return pending(current),
+    };
     if res.code == 250 {
-        res = fsm.ehlo_response();
+        let keywords = handler.ehlo_keywords(&fsm.info);
+        res = fsm.ehlo_response(keywords);
+        fsm.ehlo = true;
+        fsm.enhanced_status_codes = true;
+        fsm.info.set_helo_domain(domain);
     }
     match fsm.auth_state {
         AuthState::Unavailable => next_state(current, res, || {
@@ -188,13 +554,14 @@ fn authenticate_plain(
     authentication_id: &str,
     password: &str,
 ) -> Response {
-    let auth_res = handler.auth_plain(authorization_id, authentication_id, password);
-    fsm.auth_state = ternary!(
-        auth_res.code == 235,
-        AuthState::Authenticated,
-        AuthState::RequiresAuth
+    let auth_res = handler.auth_plain(&fsm.info, authorization_id, authentication_id, password);
+    // The client acts as the authorization identity, if it gave one
+    let identity = ternary!(
+        authorization_id.is_empty(),
+        authentication_id,
+        authorization_id
     );
-    auth_res
+    auth_outcome(fsm, auth_res, Some(identity))
 }
 
 fn authenticate_login(
@@ -203,13 +570,167 @@ fn authenticate_login(
     username: &str,
     password: &str,
 ) -> Response {
-    let auth_res = handler.auth_login(username, password);
+    let auth_res = handler.auth_login(&fsm.info, username, password);
+    auth_outcome(fsm, auth_res, Some(username))
+}
+
+fn authenticate_cram_md5(
+    fsm: &mut StateMachine,
+    handler: &mut dyn Handler,
+    challenge: &str,
+    response: &[u8],
+) -> Response {
+    let (username, digest) = match decode_cram_md5(response) {
+        Some(credentials) => credentials,
+        None => return auth_outcome(fsm, MALFORMED_AUTH_RESPONSE, None),
+    };
+    let auth_res = match handler.auth_cram_md5_secret(&fsm.info, &username) {
+        Some(secret) if cram_md5_verify(&secret, challenge, &digest) => AUTH_OK,
+        _ => INVALID_CREDENTIALS,
+    };
+    auth_outcome(fsm, auth_res, Some(&username))
+}
+
+// Start a SCRAM-SHA-256 exchange with the client-first-message
+fn start_scram_sha256(
+    domain: String,
+    fsm: &mut StateMachine,
+    handler: &mut dyn Handler,
+    response: &[u8],
+) -> (Response, Option<Box<dyn State>>) {
+    let client_first = base64::decode(response)
+        .ok()
+        .and_then(|msg| decode_scram_client_first(&msg));
+    let client_first = match client_first {
+        Some(client_first) => client_first,
+        None => return auth_done(domain, auth_outcome(fsm, MALFORMED_AUTH_RESPONSE, None)),
+    };
+    let username = client_first.username.clone();
+    let credentials = handler
+        .auth_scram_sha256_credentials(&fsm.info, &username)
+        .unwrap_or_else(|| scram_unknown_user(&username));
+    let (server, server_first) = ScramServer::new(client_first, credentials, &scram_nonce());
+    (
+        auth_challenge(server_first.as_bytes()),
+        Some(Box::new(Auth {
+            domain,
+            exchange: Exchange::ScramFinal { server, username },
+        })),
+    )
+}
+
+// Authenticate with a bearer token, decoded from the client response by the given function
+fn authenticate_bearer(
+    domain: String,
+    fsm: &mut StateMachine,
+    handler: &mut dyn Handler,
+    response: &[u8],
+    decode: fn(&[u8]) -> Option<(String, String)>,
+) -> (Response, Option<Box<dyn State>>) {
+    let credentials = base64::decode(response).ok().and_then(|msg| decode(&msg));
+    let (user, token) = match credentials {
+        Some(credentials) => credentials,
+        None => return auth_done(domain, auth_outcome(fsm, MALFORMED_AUTH_RESPONSE, None)),
+    };
+    let res = handler.auth_bearer(&fsm.info, &user, &token);
+    let res = auth_outcome(fsm, res, Some(&user));
+    if res.is_error && res.action != Action::Close {
+        // The client acknowledges the error challenge before getting the response
+        (
+            auth_challenge(BEARER_ERROR.as_bytes()),
+            Some(Box::new(Auth {
+                domain,
+                exchange: Exchange::BearerFailed(res),
+            })),
+        )
+    } else {
+        auth_done(domain, res)
+    }
+}
+
+// Authenticate with the TLS client certificate and the base64 encoded authorization identity
+fn authenticate_external(
+    fsm: &mut StateMachine,
+    handler: &mut dyn Handler,
+    response: &[u8],
+) -> Response {
+    // A single "=" is an empty initial response (RFC 4954)
+    let authorization_id = match response {
+        b"=" => Some(String::new()),
+        _ => decode_sasl_external(response),
+    };
+    let authorization_id = match authorization_id {
+        Some(authorization_id) => authorization_id,
+        None => return auth_outcome(fsm, MALFORMED_AUTH_RESPONSE, None),
+    };
+    match handler.auth_external(&fsm.info, &authorization_id, &fsm.peer_certificates) {
+        Ok(identity) => auth_outcome(fsm, AUTH_OK, Some(&identity)),
+        Err(res) => auth_outcome(fsm, res, None),
+    }
+}
+
+// Take the next step of a registered mechanism with the base64 encoded client response
+fn sasl_step(
+    domain: String,
+    fsm: &mut StateMachine,
+    handler: &mut dyn Handler,
+    mechanism: String,
+    mut exchange: Box<dyn SaslExchange>,
+    response: Option<&[u8]>,
+) -> (Response, Option<Box<dyn State>>) {
+    // A single "=" is an empty initial response (RFC 4954)
+    let decoded = match response {
+        Some(b"=") => Some(Vec::new()),
+        Some(response) => match base64::decode(response) {
+            Ok(decoded) => Some(decoded),
+            Err(_) => return auth_done(domain, auth_outcome(fsm, MALFORMED_AUTH_RESPONSE, None)),
+        },
+        None => None,
+    };
+    match exchange.step(decoded.as_deref()) {
+        SaslStep::Challenge(challenge) => (
+            auth_challenge(&challenge),
+            Some(Box::new(Auth {
+                domain,
+                exchange: Exchange::Sasl {
+                    mechanism,
+                    exchange,
+                },
+            })),
+        ),
+        SaslStep::Success(identity) => {
+            let res = handler.auth_sasl(&fsm.info, &mechanism, &identity);
+            auth_done(domain, auth_outcome(fsm, res, Some(&identity)))
+        }
+        SaslStep::Failure(res) => auth_done(domain, auth_outcome(fsm, res, None)),
+    }
+}
+
+// Record the outcome of an authentication attempt by the given identity
+fn auth_outcome(fsm: &mut StateMachine, res: Response, identity: Option<&str>) -> Response {
+    let authenticated = res.code == 235;
     fsm.auth_state = ternary!(
-        auth_res.code == 235,
+        authenticated,
         AuthState::Authenticated,
         AuthState::RequiresAuth
     );
-    auth_res
+    let identity = identity.filter(|i| authenticated && !i.is_empty());
+    fsm.info.set_auth_identity(identity.map(str::to_string));
+    res
+}
+
+// Return to the hello states once authentication has finished
+fn auth_done(domain: String, res: Response) -> (Response, Option<Box<dyn State>>) {
+    if res.is_error {
+        (res, Some(Box::new(HelloAuth { domain })))
+    } else {
+        (res, Some(Box::new(Hello { domain })))
+    }
+}
+
+// A challenge carrying base64 encoded data
+fn auth_challenge(data: &[u8]) -> Response {
+    Response::custom(334, None, base64::encode(data))
 }
 
 //------------------------------------------------------------------------------
@@ -231,9 +752,22 @@ impl State for Idle {
         match cmd {
             Cmd::StartedTls => {
                 fsm.tls = TlsState::Active;
+                // The client must say EHLO and authenticate again over TLS
+                fsm.ehlo = false;
+                fsm.enhanced_status_codes = false;
+                fsm.info.start_tls();
+                if let AuthState::Authenticated = fsm.auth_state {
+                    fsm.auth_state = AuthState::RequiresAuth;
+                }
                 (EMPTY_RESPONSE, Some(self))
             }
-            Cmd::Rset => (OK, Some(self)),
+            Cmd::Rset => {
+                handler.rset(&fsm.info);
+                fsm.info.end_transaction();
+                (OK, Some(self))
+            }
+            Cmd::Xclient { ref attributes } => handle_xclient(self, fsm, handler, attributes),
+            Cmd::Xforward { ref attributes } => handle_xforward(self, fsm, attributes),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -259,21 +793,62 @@ impl State for Hello {
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
             Cmd::Mail {
-                reverse_path,
-                is8bit,
+                ref reverse_path,
+                body,
+                size,
+                smtputf8,
+                require_tls,
+                ref dsn,
+                ref params,
             } => {
-                let res = handler.mail(fsm.ip, &self.domain, reverse_path);
+                if fsm.exceeds_max_messages() {
+                    return (TOO_MANY_MESSAGES, None);
+                }
+                if !fsm.supports_params(params, MAIL_KEYWORDS) {
+                    return (UNKNOWN_PARAMETER, Some(self));
+                }
+                if fsm.exceeds_max_size(size.unwrap_or_default()) {
+                    return (MESSAGE_TOO_LARGE, Some(self));
+                }
+                if !smtputf8 && !reverse_path.is_ascii() {
+                    return (NON_ASCII_ADDRESS, Some(self));
+                }
+                // REQUIRETLS is only offered after STARTTLS (RFC 8689). Without TLS it is
+                // never offered, so it is an unknown parameter.
+                if require_tls {
+                    match fsm.tls {
+                        TlsState::Active => (),
+                        TlsState::Inactive => return (TLS_REQUIRED, Some(self)),
+                        TlsState::Unavailable => return (UNKNOWN_PARAMETER, Some(self)),
+                    }
+                }
+                let res = match fsm.decide(
+                    || Event::NeedMailDecision {
+                        from: reverse_path.clone(),
+                        dsn: dsn.clone(),
+                        params: params.clone(),
+                    },
+                    |info| handler.mail(info, reverse_path, dsn, params),
+                ) {
+                    Some(res) => res,
+                    None => return pending(self),
+                };
                 transform_state(self, res, |s| {
                     Box::new(Mail {
                         domain: s.domain,
-                        reverse_path: reverse_path.to_owned(),
-                        is8bit,
+                        reverse_path: reverse_path.clone(),
+                        body,
+                        smtputf8,
+                        require_tls,
                     })
                 })
             }
             Cmd::StartTls if fsm.tls == TlsState::Inactive => (START_TLS, Some(Box::new(Idle {}))),
-            Cmd::Vrfy => (VERIFY_RESPONSE, Some(self)),
-            Cmd::Rset => handle_rset(fsm, &self.domain),
+            Cmd::Xclient { ref attributes } => handle_xclient(self, fsm, handler, attributes),
+            Cmd::Xforward { ref attributes } => handle_xforward(self, fsm, attributes),
+            Cmd::Vrfy { arg } => (handler.vrfy(&fsm.info, arg), Some(self)),
+            Cmd::Expn { list } => (handler.expn(&fsm.info, list), Some(self)),
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -299,49 +874,135 @@ impl State for HelloAuth {
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
             Cmd::StartTls => (START_TLS, Some(Box::new(Idle {}))),
+            Cmd::Xclient { ref attributes } => handle_xclient(self, fsm, handler, attributes),
+            Cmd::Xforward { ref attributes } => handle_xforward(self, fsm, attributes),
             Cmd::AuthPlain {
                 ref authorization_id,
                 ref authentication_id,
                 ref password,
-            } if fsm.allow_auth_plain() => {
+            } if fsm.allow_auth(&AuthMechanism::Plain) => {
                 let res =
                     authenticate_plain(fsm, handler, authorization_id, authentication_id, password);
                 transform_state(self, res, |s| Box::new(Hello { domain: s.domain }))
             }
-            Cmd::AuthPlainEmpty if fsm.allow_auth_plain() => {
+            Cmd::AuthPlainEmpty if fsm.allow_auth(&AuthMechanism::Plain) => {
                 let domain = self.domain.clone();
                 (
                     EMPTY_AUTH_CHALLENGE,
                     Some(Box::new(Auth {
                         domain,
-                        mechanism: AuthMechanism::Plain,
-                        username: None,
+                        exchange: Exchange::Plain,
                     })),
                 )
             }
-            Cmd::AuthLogin { ref username } if fsm.allow_auth_login() => {
+            Cmd::AuthLogin { ref username } if fsm.allow_auth(&AuthMechanism::Login) => {
                 let domain = self.domain.clone();
                 (
                     PASSWORD_AUTH_CHALLENGE,
                     Some(Box::new(Auth {
                         domain,
-                        mechanism: AuthMechanism::Login,
-                        username: Some(username.clone()),
+                        exchange: Exchange::Login {
+                            username: Some(username.clone()),
+                        },
                     })),
                 )
             }
-            Cmd::AuthLoginEmpty if fsm.allow_auth_login() => {
+            Cmd::AuthLoginEmpty if fsm.allow_auth(&AuthMechanism::Login) => {
                 let domain = self.domain.clone();
                 (
                     USERNAME_AUTH_CHALLENGE,
                     Some(Box::new(Auth {
                         domain,
-                        mechanism: AuthMechanism::Login,
-                        username: None,
+                        exchange: Exchange::Login { username: None },
+                    })),
+                )
+            }
+            Cmd::AuthCramMd5 if fsm.allow_auth(&AuthMechanism::CramMd5) => {
+                let challenge = cram_md5_challenge(&fsm.name);
+                let res = auth_challenge(challenge.as_bytes());
+                let domain = self.domain.clone();
+                (
+                    res,
+                    Some(Box::new(Auth {
+                        domain,
+                        exchange: Exchange::CramMd5 { challenge },
+                    })),
+                )
+            }
+            Cmd::AuthScramSha256 {
+                initial: Some(initial),
+            } if fsm.allow_auth(&AuthMechanism::ScramSha256) => {
+                start_scram_sha256(self.domain, fsm, handler, initial)
+            }
+            Cmd::AuthOAuthBearer {
+                initial: Some(initial),
+            } if fsm.allow_auth(&AuthMechanism::OAuthBearer) => {
+                authenticate_bearer(self.domain, fsm, handler, initial, decode_oauthbearer)
+            }
+            Cmd::AuthXOAuth2 {
+                initial: Some(initial),
+            } if fsm.allow_auth(&AuthMechanism::XOAuth2) => {
+                authenticate_bearer(self.domain, fsm, handler, initial, decode_xoauth2)
+            }
+            Cmd::AuthOAuthBearer { initial: None }
+                if fsm.allow_auth(&AuthMechanism::OAuthBearer) =>
+            {
+                let domain = self.domain.clone();
+                (
+                    EMPTY_AUTH_CHALLENGE,
+                    Some(Box::new(Auth {
+                        domain,
+                        exchange: Exchange::OAuthBearer,
+                    })),
+                )
+            }
+            Cmd::AuthXOAuth2 { initial: None } if fsm.allow_auth(&AuthMechanism::XOAuth2) => {
+                let domain = self.domain.clone();
+                (
+                    EMPTY_AUTH_CHALLENGE,
+                    Some(Box::new(Auth {
+                        domain,
+                        exchange: Exchange::XOAuth2,
                     })),
                 )
             }
-            Cmd::Rset => handle_rset(fsm, &self.domain),
+            Cmd::AuthScramSha256 { initial: None }
+                if fsm.allow_auth(&AuthMechanism::ScramSha256) =>
+            {
+                let domain = self.domain.clone();
+                (
+                    EMPTY_AUTH_CHALLENGE,
+                    Some(Box::new(Auth {
+                        domain,
+                        exchange: Exchange::ScramFirst,
+                    })),
+                )
+            }
+            Cmd::AuthExternal {
+                initial: Some(initial),
+            } if fsm.allow_auth(&AuthMechanism::External) => {
+                let res = authenticate_external(fsm, handler, initial);
+                auth_done(self.domain, res)
+            }
+            Cmd::AuthExternal { initial: None } if fsm.allow_auth(&AuthMechanism::External) => {
+                let domain = self.domain.clone();
+                (
+                    EMPTY_AUTH_CHALLENGE,
+                    Some(Box::new(Auth {
+                        domain,
+                        exchange: Exchange::External,
+                    })),
+                )
+            }
+            Cmd::AuthSasl { mechanism, initial } => match fsm.sasl_mechanism(mechanism) {
+                Some(mechanism) => {
+                    let exchange = mechanism.exchange();
+                    let name = mechanism.name().to_string();
+                    sasl_step(self.domain, fsm, handler, name, exchange, initial)
+                }
+                None => (UNKNOWN_AUTH_MECHANISM, Some(self)),
+            },
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -351,8 +1012,39 @@ impl State for HelloAuth {
 
 struct Auth {
     domain: String,
-    mechanism: AuthMechanism,
-    username: Option<String>,
+    exchange: Exchange,
+}
+
+// The progress of a SASL exchange
+enum Exchange {
+    Plain,
+    Login {
+        username: Option<String>,
+    },
+    CramMd5 {
+        challenge: String,
+    },
+    // Waiting for the SCRAM client-first-message
+    ScramFirst,
+    // Waiting for the SCRAM client-final-message
+    ScramFinal {
+        server: ScramServer,
+        username: String,
+    },
+    // The server-final-message was sent, waiting for the client to acknowledge it
+    ScramVerified {
+        username: String,
+    },
+    OAuthBearer,
+    XOAuth2,
+    // The bearer error challenge was sent, waiting for the client to acknowledge it
+    BearerFailed(Response),
+    External,
+    // A mechanism registered by the user of the library
+    Sasl {
+        mechanism: String,
+        exchange: Box<dyn SaslExchange>,
+    },
 }
 
 impl State for Auth {
@@ -362,14 +1054,18 @@ impl State for Auth {
     }
 
     fn handle(
-        mut self: Box<Self>,
+        self: Box<Self>,
         fsm: &mut StateMachine,
         handler: &mut dyn Handler,
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
+        let domain = self.domain;
         match cmd {
-            Cmd::AuthResponse { response } => match self.mechanism {
-                AuthMechanism::Plain => {
+            Cmd::AuthResponse { response: b"*" } => {
+                auth_done(domain, auth_outcome(fsm, AUTH_CANCELLED, None))
+            }
+            Cmd::AuthResponse { response } => match self.exchange {
+                Exchange::Plain => {
                     let creds = decode_sasl_plain(response);
                     let res = authenticate_plain(
                         fsm,
@@ -378,44 +1074,75 @@ impl State for Auth {
                         &creds.authentication_id,
                         &creds.password,
                     );
-                    if res.is_error {
-                        (
-                            res,
-                            Some(Box::new(HelloAuth {
-                                domain: self.domain,
-                            })),
-                        )
+                    auth_done(domain, res)
+                }
+                Exchange::Login { username } => {
+                    let credential = decode_sasl_login(response);
+                    if let Some(username) = username {
+                        let res = authenticate_login(fsm, handler, &username, &credential);
+                        auth_done(domain, res)
                     } else {
                         (
-                            res,
-                            Some(Box::new(Hello {
-                                domain: self.domain,
+                            PASSWORD_AUTH_CHALLENGE,
+                            Some(Box::new(Auth {
+                                domain,
+                                exchange: Exchange::Login {
+                                    username: Some(credential),
+                                },
                             })),
                         )
                     }
                 }
-                AuthMechanism::Login => {
-                    let credential = decode_sasl_login(response);
-                    if let Some(username) = self.username {
-                        let res = authenticate_login(fsm, handler, &username, &credential);
-                        let domain = self.domain.clone();
-                        if res.is_error {
-                            (res, Some(Box::new(HelloAuth { domain })))
-                        } else {
-                            (res, Some(Box::new(Hello { domain })))
-                        }
-                    } else {
-                        self.username = Some(credential);
-                        (PASSWORD_AUTH_CHALLENGE, Some(self))
+                Exchange::CramMd5 { challenge } => {
+                    let res = authenticate_cram_md5(fsm, handler, &challenge, response);
+                    auth_done(domain, res)
+                }
+                Exchange::ScramFirst => start_scram_sha256(domain, fsm, handler, response),
+                Exchange::ScramFinal { server, username } => {
+                    let server_final = base64::decode(response)
+                        .ok()
+                        .and_then(|msg| decode_scram_client_final(&msg))
+                        .and_then(|client_final| server.verify(&client_final));
+                    match server_final {
+                        Some(server_final) => (
+                            auth_challenge(server_final.as_bytes()),
+                            Some(Box::new(Auth {
+                                domain,
+                                exchange: Exchange::ScramVerified { username },
+                            })),
+                        ),
+                        None => auth_done(domain, auth_outcome(fsm, INVALID_CREDENTIALS, None)),
                     }
                 }
+                Exchange::ScramVerified { username } => {
+                    auth_done(domain, auth_outcome(fsm, AUTH_OK, Some(&username)))
+                }
+                Exchange::OAuthBearer => {
+                    authenticate_bearer(domain, fsm, handler, response, decode_oauthbearer)
+                }
+                Exchange::XOAuth2 => {
+                    authenticate_bearer(domain, fsm, handler, response, decode_xoauth2)
+                }
+                Exchange::BearerFailed(res) => auth_done(domain, res),
+                Exchange::External => {
+                    let res = authenticate_external(fsm, handler, response);
+                    auth_done(domain, res)
+                }
+                Exchange::Sasl {
+                    mechanism,
+                    exchange,
+                } => sasl_step(domain, fsm, handler, mechanism, exchange, Some(response)),
             },
-            _ => unhandled(self),
+            _ => unhandled(Box::new(Auth {
+                domain,
+                exchange: self.exchange,
+            })),
         }
     }
 
     fn process_line<'a>(
         &mut self,
+        _info: &SessionInfo,
         _handler: &mut dyn Handler,
         line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
@@ -424,14 +1151,21 @@ impl State for Auth {
             .map(|r| Left(Cmd::AuthResponse { response: r }))
             .unwrap_or_else(Right)
     }
+
+    fn input(&self) -> Input {
+        Input::AuthResponse
+    }
 }
 
 //------------------------------------------------------------------------------
 
 struct Mail {
     domain: String,
-    reverse_path: String,
-    is8bit: bool,
+    reverse_path: Path,
+    body: BodyType,
+    smtputf8: bool,
+    // The message must only be relayed over verified TLS (RFC 8689)
+    require_tls: bool,
 }
 
 impl State for Mail {
@@ -447,19 +1181,42 @@ impl State for Mail {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
-            Cmd::Rcpt { forward_path } => {
-                let res = handler.rcpt(forward_path);
+            Cmd::Rcpt {
+                ref forward_path, ..
+            } if !self.smtputf8 && !forward_path.is_ascii() => (NON_ASCII_ADDRESS, Some(self)),
+            Cmd::Rcpt { ref params, .. } if !fsm.supports_params(params, RCPT_KEYWORDS) => {
+                (UNKNOWN_PARAMETER, Some(self))
+            }
+            Cmd::Rcpt { .. } if fsm.exceeds_max_recipients(0) => (TOO_MANY_RECIPIENTS, Some(self)),
+            Cmd::Rcpt {
+                forward_path,
+                ref dsn,
+                ref params,
+            } => {
+                let res = match fsm.decide(
+                    || Event::NeedRcptDecision {
+                        to: forward_path.clone(),
+                        dsn: dsn.clone(),
+                        params: params.clone(),
+                    },
+                    |info| handler.rcpt(info, &forward_path, dsn, params),
+                ) {
+                    Some(res) => res,
+                    None => return pending(self),
+                };
                 transform_state(self, res, |s| {
-                    let fp = vec![forward_path.to_owned()];
+                    let fp = vec![forward_path];
                     Box::new(Rcpt {
                         domain: s.domain,
                         reverse_path: s.reverse_path,
-                        is8bit: s.is8bit,
+                        body: s.body,
+                        smtputf8: s.smtputf8,
+                        require_tls: s.require_tls,
                         forward_path: fp,
                     })
                 })
             }
-            Cmd::Rset => handle_rset(fsm, &self.domain),
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -469,9 +1226,23 @@ impl State for Mail {
 
 struct Rcpt {
     domain: String,
-    reverse_path: String,
-    is8bit: bool,
-    forward_path: Vec<String>,
+    reverse_path: Path,
+    body: BodyType,
+    smtputf8: bool,
+    require_tls: bool,
+    forward_path: Vec<Path>,
+}
+
+impl Rcpt {
+    fn data_start_event(&self) -> Event {
+        Event::NeedDataStartDecision {
+            from: self.reverse_path.clone(),
+            is8bit: self.body.is8bit(),
+            smtputf8: self.smtputf8,
+            require_tls: self.require_tls,
+            to: self.forward_path.clone(),
+        }
+    }
 }
 
 impl State for Rcpt {
@@ -487,30 +1258,110 @@ impl State for Rcpt {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
+            // Binary messages can only be sent with BDAT
+            Cmd::Data if self.body == BodyType::BinaryMime => (BAD_SEQUENCE_COMMANDS, Some(self)),
             Cmd::Data => {
-                let res = handler.data_start(
-                    &self.domain,
-                    &self.reverse_path,
-                    self.is8bit,
-                    &self.forward_path,
-                );
+                let res = match fsm.decide(
+                    || self.data_start_event(),
+                    |info| {
+                        handler.data_start(
+                            info,
+                            &self.reverse_path,
+                            self.body.is8bit(),
+                            self.smtputf8,
+                            self.require_tls,
+                            &self.forward_path,
+                        )
+                    },
+                ) {
+                    Some(res) => res,
+                    None => return pending(self),
+                };
                 let res = ternary!(res.is_error, res, START_DATA);
-                transform_state(self, res, |s| Box::new(Data { domain: s.domain }))
+                transform_state(self, res, |s| {
+                    let error = fsm.write_received_header(handler, &s.forward_path);
+                    Box::new(Data {
+                        domain: s.domain,
+                        forward_path: s.forward_path,
+                        max_size: fsm.max_size,
+                        line_policy: fsm.line_policy,
+                        size: 0,
+                        error,
+                        after_bare_line_ending: false,
+                    })
+                })
             }
-            Cmd::Rcpt { forward_path } => {
-                let res = handler.rcpt(forward_path);
+            Cmd::Bdat { size, last } => {
+                let res = match fsm.decide(
+                    || self.data_start_event(),
+                    |info| {
+                        handler.data_start(
+                            info,
+                            &self.reverse_path,
+                            self.body.is8bit(),
+                            self.smtputf8,
+                            self.require_tls,
+                            &self.forward_path,
+                        )
+                    },
+                ) {
+                    Some(res) => res,
+                    None => return pending(self),
+                };
+                if res.is_error {
+                    return discard_chunk(self, size, res);
+                }
+                let error = fsm.write_received_header(handler, &self.forward_path);
+                let bdat = Box::new(Bdat {
+                    domain: self.domain,
+                    forward_path: self.forward_path,
+                    max_size: fsm.max_size,
+                    size: 0,
+                    remaining: 0,
+                    last: false,
+                    error,
+                });
+                bdat.start_chunk(fsm, handler, size, last)
+            }
+            Cmd::Rcpt {
+                ref forward_path, ..
+            } if !self.smtputf8 && !forward_path.is_ascii() => (NON_ASCII_ADDRESS, Some(self)),
+            Cmd::Rcpt { ref params, .. } if !fsm.supports_params(params, RCPT_KEYWORDS) => {
+                (UNKNOWN_PARAMETER, Some(self))
+            }
+            Cmd::Rcpt { .. } if fsm.exceeds_max_recipients(self.forward_path.len()) => {
+                (TOO_MANY_RECIPIENTS, Some(self))
+            }
+            Cmd::Rcpt {
+                forward_path,
+                ref dsn,
+                ref params,
+            } => {
+                let res = match fsm.decide(
+                    || Event::NeedRcptDecision {
+                        to: forward_path.clone(),
+                        dsn: dsn.clone(),
+                        params: params.clone(),
+                    },
+                    |info| handler.rcpt(info, &forward_path, dsn, params),
+                ) {
+                    Some(res) => res,
+                    None => return pending(self),
+                };
                 transform_state(self, res, |s| {
                     let mut fp = s.forward_path;
-                    fp.push(forward_path.to_owned());
+                    fp.push(forward_path);
                     Box::new(Rcpt {
                         domain: s.domain,
                         reverse_path: s.reverse_path,
-                        is8bit: s.is8bit,
+                        body: s.body,
+                        smtputf8: s.smtputf8,
+                        require_tls: s.require_tls,
                         forward_path: fp,
                     })
                 })
             }
-            Cmd::Rset => handle_rset(fsm, &self.domain),
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -520,6 +1371,21 @@ impl State for Rcpt {
 
 struct Data {
     domain: String,
+    forward_path: Vec<Path>,
+    max_size: Option<usize>,
+    line_policy: LinePolicy,
+    // Number of bytes received so far
+    size: usize,
+    // Error to report at the end of data
+    error: Option<Response>,
+    // Did the previous line end with a bare CR or LF?
+    after_bare_line_ending: bool,
+}
+
+impl Data {
+    fn too_large(&self) -> bool {
+        self.max_size.map(|max| self.size > max).unwrap_or(false)
+    }
 }
 
 impl State for Data {
@@ -530,14 +1396,24 @@ impl State for Data {
 
     fn handle(
         self: Box<Self>,
-        _fsm: &mut StateMachine,
+        fsm: &mut StateMachine,
         handler: &mut dyn Handler,
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
             Cmd::DataEnd => {
-                let res = handler.data_end();
-                transform_state(self, res, |s| Box::new(Hello { domain: s.domain }))
+                if let Some(res) = self.error {
+                    let res = data_failed(fsm, res, &self.forward_path);
+                    abort_transaction(fsm, handler, res, self.domain)
+                } else if self.too_large() {
+                    let res = data_failed(fsm, MESSAGE_TOO_LARGE, &self.forward_path);
+                    abort_transaction(fsm, handler, res, self.domain)
+                } else {
+                    match end_of_data(fsm, handler, &self.forward_path) {
+                        Some(res) => end_transaction(fsm, res, self.domain),
+                        None => pending(self),
+                    }
+                }
             }
             _ => unhandled(self),
         }
@@ -545,17 +1421,43 @@ impl State for Data {
 
     fn process_line<'a>(
         &mut self,
+        info: &SessionInfo,
         handler: &mut dyn Handler,
-        mut line: &'a [u8],
+        line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
-        if line == b".\r\n" {
+        let after_bare_line_ending = self.after_bare_line_ending;
+        self.after_bare_line_ending = ends_with_bare_line_ending(line);
+        let smuggled = after_bare_line_ending && self.line_policy != LinePolicy::Lenient;
+        if line == b".\r\n" && smuggled && self.line_policy == LinePolicy::Strict {
+            trace!("> _data_");
+            self.error = Some(SMUGGLED_DATA_END);
+            Left(Cmd::DataEnd)
+        } else if line == b".\r\n" && !smuggled {
             trace!("> _data_");
             Left(Cmd::DataEnd)
         } else {
+            if line == b".\r\n" {
+                // Not the end of data, but a server that accepts bare line endings
+                // would see it as one. The message is rejected at the real end of data.
+                self.error.get_or_insert(MESSAGE_BARE_LINE_ENDING);
+            }
+            let checked = match check_text_line(self.line_policy, line) {
+                Ok(checked) => checked,
+                Err(res) => {
+                    self.error.get_or_insert(res);
+                    Cow::Borrowed(line)
+                }
+            };
+            let mut line: &[u8] = &checked;
             if line.starts_with(b".") {
                 line = &line[1..];
             }
-            match handler.data(line) {
+            self.size = self.size.saturating_add(line.len());
+            if self.error.is_some() || self.too_large() {
+                // Discard the rest of the message and reject it at the end of data
+                return Right(EMPTY_RESPONSE);
+            }
+            match handler.data(info, line) {
                 Ok(_) => Right(EMPTY_RESPONSE),
                 Err(e) => {
                     error!("Error saving message: {}", e);
@@ -564,44 +1466,292 @@ impl State for Data {
             }
         }
     }
+
+    fn input(&self) -> Input {
+        Input::Text
+    }
+}
+//------------------------------------------------------------------------------
+
+// Receives a message sent in one or more BDAT chunks
+struct Bdat {
+    domain: String,
+    forward_path: Vec<Path>,
+    max_size: Option<usize>,
+    // Number of bytes received so far
+    size: usize,
+    // Number of bytes remaining in the current chunk
+    remaining: usize,
+    // Is the current chunk the last one?
+    last: bool,
+    // Error to report at the end of the current chunk
+    error: Option<Response>,
+}
+
+impl Bdat {
+    fn too_large(&self) -> bool {
+        self.max_size.map(|max| self.size > max).unwrap_or(false)
+    }
+
+    fn start_chunk(
+        mut self: Box<Self>,
+        fsm: &mut StateMachine,
+        handler: &mut dyn Handler,
+        size: usize,
+        last: bool,
+    ) -> (Response, Option<Box<dyn State>>) {
+        self.remaining = size;
+        self.last = last;
+        if size == 0 {
+            self.end_chunk(fsm, handler)
+        } else {
+            (EMPTY_RESPONSE, Some(self))
+        }
+    }
+
+    fn end_chunk(
+        self: Box<Self>,
+        fsm: &mut StateMachine,
+        handler: &mut dyn Handler,
+    ) -> (Response, Option<Box<dyn State>>) {
+        // A failed chunk fails the whole transaction
+        let failed = self.error.clone().or_else(|| {
+            if self.too_large() {
+                Some(MESSAGE_TOO_LARGE)
+            } else {
+                None
+            }
+        });
+        if let Some(res) = failed {
+            let res = ternary!(self.last, data_failed(fsm, res, &self.forward_path), res);
+            abort_transaction(fsm, handler, res, self.domain)
+        } else if self.last {
+            match end_of_data(fsm, handler, &self.forward_path) {
+                Some(res) => end_transaction(fsm, res, self.domain),
+                None => pending(self),
+            }
+        } else {
+            let res = Response::custom(
+                250,
+                Some(EnhancedStatus::new(2, 0, 0)),
+                format!("{} octets received", self.size),
+            );
+            (res, Some(self))
+        }
+    }
+}
+
+impl State for Bdat {
+    #[cfg(test)]
+    fn id(&self) -> SmtpState {
+        SmtpState::Bdat
+    }
+
+    fn handle(
+        self: Box<Self>,
+        fsm: &mut StateMachine,
+        handler: &mut dyn Handler,
+        cmd: Cmd,
+    ) -> (Response, Option<Box<dyn State>>) {
+        match cmd {
+            Cmd::ChunkEnd => self.end_chunk(fsm, handler),
+            Cmd::Bdat { size, last } => self.start_chunk(fsm, handler, size, last),
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
+            _ => default_handler(self, fsm, handler, &cmd),
+        }
+    }
+
+    fn process_line<'a>(
+        &mut self,
+        info: &SessionInfo,
+        handler: &mut dyn Handler,
+        line: &'a [u8],
+    ) -> Either<Cmd<'a>, Response> {
+        if self.remaining == 0 {
+            trace!("> {}", String::from_utf8_lossy(line));
+            return parse(line).map(Left).unwrap_or_else(Right);
+        }
+        let chunk = &line[..line.len().min(self.remaining)];
+        self.remaining -= chunk.len();
+        self.size = self.size.saturating_add(chunk.len());
+        if self.error.is_none() && !self.too_large() {
+            if let Err(e) = handler.data(info, chunk) {
+                error!("Error saving message: {}", e);
+                self.error = Some(TRANSACTION_FAILED);
+            }
+        }
+        if self.remaining == 0 {
+            trace!("> _chunk_");
+            Left(Cmd::ChunkEnd)
+        } else {
+            Right(EMPTY_RESPONSE)
+        }
+    }
+
+    fn chunk_remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn input(&self) -> Input {
+        ternary!(self.remaining > 0, Input::Chunk, Input::Command)
+    }
+}
+
+//------------------------------------------------------------------------------
+
+// Reads and drops a BDAT chunk that cannot be accepted
+struct BdatDiscard {
+    remaining: usize,
+    // The response to send once the chunk has been read
+    response: Response,
+    // The state to return to once the chunk has been read
+    previous: Box<dyn State>,
+}
+
+impl State for BdatDiscard {
+    #[cfg(test)]
+    fn id(&self) -> SmtpState {
+        SmtpState::BdatDiscard
+    }
+
+    fn handle(
+        self: Box<Self>,
+        _fsm: &mut StateMachine,
+        _handler: &mut dyn Handler,
+        cmd: Cmd,
+    ) -> (Response, Option<Box<dyn State>>) {
+        match cmd {
+            Cmd::ChunkEnd => (self.response, Some(self.previous)),
+            _ => unhandled(self),
+        }
+    }
+
+    fn process_line<'a>(
+        &mut self,
+        _info: &SessionInfo,
+        _handler: &mut dyn Handler,
+        line: &'a [u8],
+    ) -> Either<Cmd<'a>, Response> {
+        self.remaining -= line.len().min(self.remaining);
+        if self.remaining == 0 {
+            trace!("> _discarded chunk_");
+            Left(Cmd::ChunkEnd)
+        } else {
+            Right(EMPTY_RESPONSE)
+        }
+    }
+
+    fn chunk_remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn input(&self) -> Input {
+        ternary!(self.remaining > 0, Input::Chunk, Input::Command)
+    }
 }
+
 //------------------------------------------------------------------------------
 
+// The session settings from the SessionBuilder
+pub(crate) struct Config {
+    pub name: String,
+    pub auth_mechanisms: Vec<AuthMechanism>,
+    pub sasl_mechanisms: Vec<Arc<dyn SaslMechanism>>,
+    pub allow_start_tls: bool,
+    pub insecure_allow_plaintext_auth: bool,
+    pub max_size: Option<usize>,
+    pub esmtp_keywords: Vec<String>,
+    pub lmtp: bool,
+    pub line_policy: LinePolicy,
+    pub received_header: bool,
+    pub max_recipients: Option<usize>,
+    pub trusted_peers: Vec<IpAddr>,
+    pub max_bad_commands: Option<usize>,
+    pub max_messages: Option<usize>,
+}
+
 pub(crate) struct StateMachine {
-    ip: IpAddr,
+    info: SessionInfo,
+    // The name of the server
+    name: String,
     auth_mechanisms: Vec<AuthMechanism>,
+    // Mechanisms registered by the user of the library
+    sasl_mechanisms: Vec<Arc<dyn SaslMechanism>>,
     auth_state: AuthState,
     tls: TlsState,
     smtp: Option<Box<dyn State>>,
-    auth_plain: bool,
-    auth_login: bool,
     insecure_allow_plaintext_auth: bool,
+    max_size: Option<usize>,
+    // Keywords of the MAIL and RCPT parameters handled by the Handler
+    esmtp_keywords: Vec<String>,
+    lmtp: bool,
+    line_policy: LinePolicy,
+    // Add a Received: header to incoming messages
+    received_header: bool,
+    max_recipients: Option<usize>,
+    max_bad_commands: Option<usize>,
+    max_messages: Option<usize>,
+    // The client is a proxy that can use XCLIENT and XFORWARD
+    trusted_peer: bool,
+    // Number of bad commands sent by the client
+    bad_commands: usize,
+    // Number of messages accepted
+    messages: usize,
+    // Set when the client greeted with EHLO or LHLO, rather than HELO
+    ehlo: bool,
+    // Set once ENHANCEDSTATUSCODES has been offered in response to EHLO
+    enhanced_status_codes: bool,
+    // Return decisions as events rather than asking the handler
+    defer_decisions: bool,
+    // The deferred decision that the session is waiting for
+    pending: Option<Event>,
+    // The command to handle again once the deferred decision has been made
+    parked: Option<Parked>,
+    // A deferred decision that has been made
+    decision: Option<Vec<Response>>,
+    // The verified TLS client certificate chain
+    peer_certificates: Vec<Vec<u8>>,
 }
 
 impl StateMachine {
-    pub fn new(
-        ip: IpAddr,
-        auth_mechanisms: Vec<AuthMechanism>,
-        allow_start_tls: bool,
-        insecure_allow_plaintext_auth: bool,
-    ) -> Self {
+    pub fn new(ip: IpAddr, config: Config) -> Self {
         let auth_state = ternary!(
-            auth_mechanisms.is_empty(),
+            config.auth_mechanisms.is_empty() && config.sasl_mechanisms.is_empty(),
             AuthState::Unavailable,
             AuthState::RequiresAuth
         );
-        let tls = ternary!(allow_start_tls, TlsState::Inactive, TlsState::Unavailable);
-        let auth_plain = auth_mechanisms.contains(&AuthMechanism::Plain);
-        let auth_login = auth_mechanisms.contains(&AuthMechanism::Login);
+        let tls = ternary!(
+            config.allow_start_tls,
+            TlsState::Inactive,
+            TlsState::Unavailable
+        );
         Self {
-            ip,
-            auth_mechanisms,
+            info: SessionInfo::new(ip),
+            name: config.name,
+            auth_mechanisms: config.auth_mechanisms,
+            sasl_mechanisms: config.sasl_mechanisms,
             auth_state,
             tls,
             smtp: Some(Box::new(Idle {})),
-            auth_plain,
-            auth_login,
-            insecure_allow_plaintext_auth,
+            insecure_allow_plaintext_auth: config.insecure_allow_plaintext_auth,
+            max_size: config.max_size,
+            esmtp_keywords: config.esmtp_keywords,
+            lmtp: config.lmtp,
+            line_policy: config.line_policy,
+            received_header: config.received_header,
+            max_recipients: config.max_recipients,
+            max_bad_commands: config.max_bad_commands,
+            max_messages: config.max_messages,
+            trusted_peer: config.trusted_peers.contains(&ip),
+            bad_commands: 0,
+            messages: 0,
+            ehlo: false,
+            enhanced_status_codes: false,
+            defer_decisions: false,
+            pending: None,
+            parked: None,
+            decision: None,
+            peer_certificates: Vec::new(),
         }
     }
 
@@ -615,6 +1765,90 @@ impl StateMachine {
         response
     }
 
+    // Respond to a command parsed from the given line. A command that is waiting for a
+    // deferred decision is parked, to be handled again once the decision is made.
+    pub fn line_command(&mut self, handler: &mut dyn Handler, cmd: Cmd, line: &[u8]) -> Response {
+        let parked = match cmd {
+            _ if !self.defer_decisions => None,
+            Cmd::DataEnd => Some(Parked::DataEnd),
+            Cmd::ChunkEnd => Some(Parked::ChunkEnd),
+            _ => Some(Parked::Command(line.to_vec())),
+        };
+        let res = self.command(handler, cmd);
+        if self.pending.is_some() {
+            self.parked = parked;
+        }
+        res
+    }
+
+    // Handle the parked command again with the decision it was waiting for
+    pub fn resume(&mut self, handler: &mut dyn Handler, decision: Vec<Response>) -> Response {
+        let parked = match self.parked.take() {
+            Some(parked) => parked,
+            None => return INVALID_STATE,
+        };
+        self.decision = Some(decision);
+        let res = match parked {
+            Parked::Command(line) => match parse(&line) {
+                Ok(cmd) => self.line_command(handler, cmd, &line),
+                Err(res) => res,
+            },
+            Parked::DataEnd => self.line_command(handler, Cmd::DataEnd, &[]),
+            Parked::ChunkEnd => self.line_command(handler, Cmd::ChunkEnd, &[]),
+        };
+        self.decision = None;
+        res
+    }
+
+    pub fn defer_decisions(&mut self, defer: bool) {
+        self.defer_decisions = defer;
+    }
+
+    pub fn is_parked(&self) -> bool {
+        self.parked.is_some()
+    }
+
+    pub fn take_pending(&mut self) -> Option<Event> {
+        self.pending.take()
+    }
+
+    // Ask the handler whether to accept the connection and return the greeting.
+    // A refused connection is closed.
+    pub fn connect(&mut self, handler: &mut dyn Handler) -> Response {
+        let mut res = handler.connect(&self.info);
+        if res.is_error {
+            res.action = Action::Close;
+            self.smtp = None;
+            return res;
+        }
+        self.greeting()
+    }
+
+    fn greeting(&self) -> Response {
+        let protocol = ternary!(self.lmtp, "LMTP", "ESMTP");
+        Response::dynamic(220, format!("{} {}", self.name, protocol), Vec::new())
+    }
+
+    // Count responses to bad commands and close the session once there are too many
+    pub fn count_bad_commands(&mut self, res: Response) -> Response {
+        if !(500..=504).contains(&res.code) {
+            return res;
+        }
+        self.bad_commands += 1;
+        match self.max_bad_commands {
+            Some(max) if self.bad_commands > max => {
+                self.smtp = None;
+                TOO_MANY_ERRORS
+            }
+            _ => res,
+        }
+    }
+
+    pub fn disconnect(&mut self, handler: &mut dyn Handler, reason: DisconnectReason) {
+        handler.disconnect(&self.info, reason);
+        self.smtp = None;
+    }
+
     pub fn process_line<'a>(
         &mut self,
         handler: &mut dyn Handler,
@@ -623,44 +1857,245 @@ impl StateMachine {
         match self.smtp {
             Some(ref mut s) => {
                 let s: &mut dyn State = s.borrow_mut();
-                s.process_line(handler, line)
+                s.process_line(&self.info, handler, line)
             }
             None => Right(INVALID_STATE),
         }
     }
 
+    // Check a line against the line policy before it is processed. Lines of a message
+    // are checked by the Data state, which reports errors at the end of data.
+    // Commands that end with a bare LF are given a CRLF when normalizing.
+    pub fn check_line<'a>(&self, line: &'a [u8]) -> Result<Cow<'a, [u8]>, Response> {
+        let input = self.smtp.as_ref().map(|s| s.input());
+        let max_length = match input {
+            _ if self.line_policy == LinePolicy::Lenient => return Ok(Cow::Borrowed(line)),
+            Some(Input::Text) | Some(Input::Chunk) => return Ok(Cow::Borrowed(line)),
+            Some(Input::AuthResponse) => MAX_AUTH_LINE,
+            _ if line.len() >= 5 && line[..5].eq_ignore_ascii_case(b"auth ") => MAX_AUTH_LINE,
+            _ => MAX_COMMAND_LINE,
+        };
+        if line.len() > max_length {
+            return Err(LINE_TOO_LONG);
+        }
+        match line.strip_suffix(b"\n") {
+            Some(command) if !command.ends_with(b"\r") => {
+                if has_bare_line_ending(command) || self.line_policy == LinePolicy::Strict {
+                    Err(BARE_LINE_ENDING)
+                } else {
+                    let mut normalized = command.to_vec();
+                    normalized.extend_from_slice(b"\r\n");
+                    Ok(Cow::Owned(normalized))
+                }
+            }
+            _ if has_bare_line_ending(line) => Err(BARE_LINE_ENDING),
+            _ => Ok(Cow::Borrowed(line)),
+        }
+    }
+
+    pub fn set_tls_cipher(&mut self, cipher: &str) {
+        self.info.set_tls_cipher(cipher);
+    }
+
+    pub fn info(&self) -> &SessionInfo {
+        &self.info
+    }
+
+    pub fn set_peer_certificates(&mut self, certificates: Vec<Vec<u8>>) {
+        self.peer_certificates = certificates;
+    }
+
+    // Is this an LMTP session?
+    pub fn is_lmtp(&self) -> bool {
+        self.lmtp
+    }
+
+    // Should responses include enhanced status codes?
+    pub fn enhanced_status_codes(&self) -> bool {
+        self.enhanced_status_codes
+    }
+
+    // The number of bytes of binary BDAT data that the state machine is waiting for
+    pub fn chunk_remaining(&self) -> usize {
+        self.smtp.as_ref().map(|s| s.chunk_remaining()).unwrap_or(0)
+    }
+
     #[cfg(test)]
     pub fn current_state(&self) -> SmtpState {
         let id = self.smtp.as_ref().map(|s| s.id());
         id.unwrap_or(SmtpState::Invalid)
     }
 
-    fn ehlo_response(&self) -> Response {
-        let mut extensions = vec!["8BITMIME".to_string()];
-        if self.tls == TlsState::Inactive {
-            extensions.push("STARTTLS".to_string());
+    // Should a decision be made by the handler or deferred to the caller of the session?
+    fn decision<F>(&mut self, event: F) -> Decision
+    where
+        F: FnOnce() -> Event,
+    {
+        if !self.defer_decisions {
+            Decision::Ask
+        } else if let Some(res) = self.decision.take() {
+            Decision::Made(res)
+        } else {
+            self.pending = Some(event());
+            Decision::Pending
         }
+    }
 
-        if self.allow_auth() && !self.auth_mechanisms.is_empty() {
+    // Make a decision with the handler, or return None if it has been deferred
+    fn decide<E, F>(&mut self, event: E, ask: F) -> Option<Response>
+    where
+        E: FnOnce() -> Event,
+        F: FnOnce(&SessionInfo) -> Response,
+    {
+        match self.decision(event) {
+            Decision::Ask => Some(ask(&self.info)),
+            Decision::Made(responses) => Some(first_response(responses)),
+            Decision::Pending => None,
+        }
+    }
+
+    // The protocol of the session as named in Received: headers (RFC 3848)
+    fn protocol(&self) -> String {
+        let mut protocol = match (self.lmtp, self.ehlo) {
+            (true, _) => "LMTP".to_string(),
+            (false, true) => "ESMTP".to_string(),
+            (false, false) => return "SMTP".to_string(),
+        };
+        if self.tls == TlsState::Active {
+            protocol.push('S');
+        }
+        if let AuthState::Authenticated = self.auth_state {
+            protocol.push('A');
+        }
+        protocol
+    }
+
+    // Send the Received: header to the handler, if enabled, as the start of a message.
+    // Returns the response to give at the end of data if the handler failed.
+    fn write_received_header(&self, handler: &mut dyn Handler, to: &[Path]) -> Option<Response> {
+        if !self.received_header {
+            return None;
+        }
+        let header = received_header(
+            &self.info,
+            &self.name,
+            &self.protocol(),
+            to,
+            SystemTime::now(),
+        );
+        match handler.data(&self.info, header.as_bytes()) {
+            Ok(_) => None,
+            Err(e) => {
+                error!("Error saving message: {}", e);
+                Some(TRANSACTION_FAILED)
+            }
+        }
+    }
+
+    // The EHLO response, advertising the given keywords from the Handler last
+    fn ehlo_response(&self, keywords: Vec<String>) -> Response {
+        let mut extensions = vec![
+            "8BITMIME".to_string(),
+            "PIPELINING".to_string(),
+            "CHUNKING".to_string(),
+            "BINARYMIME".to_string(),
+            "SMTPUTF8".to_string(),
+            "DSN".to_string(),
+            "ENHANCEDSTATUSCODES".to_string(),
+        ];
+        if let Some(max_size) = self.max_size {
+            extensions.push(format!("SIZE {max_size}"));
+        }
+        match self.tls {
+            TlsState::Inactive => extensions.push("STARTTLS".to_string()),
+            TlsState::Active => extensions.push("REQUIRETLS".to_string()),
+            TlsState::Unavailable => (),
+        }
+
+        let allowed_auth: Vec<&str> = self
+            .auth_mechanisms
+            .iter()
+            .filter(|auth| self.allow_auth(auth))
+            .map(|auth| auth.extension())
+            .chain(
+                self.sasl_mechanisms
+                    .iter()
+                    .filter(|m| self.allow_sasl(m.as_ref()))
+                    .map(|m| m.name()),
+            )
+            .collect();
+        if !allowed_auth.is_empty() {
             let mut auth_available = "AUTH".to_string();
-            for auth in &self.auth_mechanisms {
+            for auth in allowed_auth {
                 auth_available += " ";
-                auth_available += auth.extension();
+                auth_available += auth;
             }
             extensions.push(auth_available);
         }
+        if self.trusted_peer {
+            extensions.push("XCLIENT NAME ADDR HELO LOGIN".to_string());
+            extensions.push("XFORWARD NAME ADDR PORT PROTO HELO IDENT SOURCE".to_string());
+        }
+        extensions.extend(keywords);
         Response::dynamic(250, "server offers extensions:".to_string(), extensions)
     }
 
-    fn allow_auth_plain(&self) -> bool {
-        self.auth_plain && self.allow_auth()
+    // Are all the given parameters handled by mailin or the Handler?
+    fn supports_params(&self, params: &EsmtpParams, keywords: &[&str]) -> bool {
+        params.iter().all(|(keyword, _)| {
+            keywords.contains(&keyword)
+                || self
+                    .esmtp_keywords
+                    .iter()
+                    .any(|k| k.eq_ignore_ascii_case(keyword))
+        })
+    }
+
+    fn exceeds_max_size(&self, size: usize) -> bool {
+        self.max_size.map(|max| size > max).unwrap_or(false)
     }
 
-    fn allow_auth_login(&self) -> bool {
-        self.auth_login && self.allow_auth()
+    // Would another recipient be too many?
+    fn exceeds_max_recipients(&self, recipients: usize) -> bool {
+        self.max_recipients
+            .map(|max| recipients >= max)
+            .unwrap_or(false)
     }
 
-    fn allow_auth(&self) -> bool {
-        self.insecure_allow_plaintext_auth || (self.tls == TlsState::Active)
+    // Would another mail transaction be too many?
+    fn exceeds_max_messages(&self) -> bool {
+        self.max_messages
+            .map(|max| self.messages >= max)
+            .unwrap_or(false)
+    }
+
+    // Find a registered mechanism that the client can authenticate with
+    fn sasl_mechanism(&self, name: &str) -> Option<Arc<dyn SaslMechanism>> {
+        self.sasl_mechanisms
+            .iter()
+            .find(|m| m.name().eq_ignore_ascii_case(name) && self.allow_sasl(m.as_ref()))
+            .cloned()
+    }
+
+    fn allow_sasl(&self, mechanism: &dyn SaslMechanism) -> bool {
+        !mechanism.requires_tls()
+            || self.insecure_allow_plaintext_auth
+            || (self.tls == TlsState::Active)
+    }
+
+    // Can the client authenticate with the given mechanism?
+    // Mechanisms that send the password in the clear need TLS and
+    // EXTERNAL needs a client certificate.
+    fn allow_auth(&self, mechanism: &AuthMechanism) -> bool {
+        let allowed = match mechanism {
+            AuthMechanism::External => {
+                self.tls == TlsState::Active && !self.peer_certificates.is_empty()
+            }
+            m if m.is_plaintext() => {
+                self.insecure_allow_plaintext_auth || (self.tls == TlsState::Active)
+            }
+            _ => true,
+        };
+        allowed && self.auth_mechanisms.contains(mechanism)
     }
 }
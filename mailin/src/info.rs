@@ -0,0 +1,132 @@
+use rand::Rng;
+use std::net::IpAddr;
+
+/// Information about an SMTP session, passed to every `Handler` callback.
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    id: String,
+    ip: IpAddr,
+    client_name: Option<String>,
+    helo_domain: Option<String>,
+    tls: bool,
+    tls_cipher: Option<String>,
+    auth_identity: Option<String>,
+    forwarded: Option<ForwardedClient>,
+}
+
+impl SessionInfo {
+    pub(crate) fn new(ip: IpAddr) -> Self {
+        let id: u64 = rand::thread_rng().gen();
+        Self {
+            id: format!("{id:016x}"),
+            ip,
+            client_name: None,
+            helo_domain: None,
+            tls: false,
+            tls_cipher: None,
+            auth_identity: None,
+            forwarded: None,
+        }
+    }
+
+    /// A random identifier for the session, e.g. for correlating log messages
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The IP address of the client
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// The domain the client gave with HELO, EHLO or LHLO
+    pub fn helo_domain(&self) -> Option<&str> {
+        self.helo_domain.as_deref()
+    }
+
+    /// The host name of the client, as given by a proxy with XCLIENT
+    pub fn client_name(&self) -> Option<&str> {
+        self.client_name.as_deref()
+    }
+
+    /// Is the session using TLS?
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    /// The name of the negotiated TLS cipher suite, if known
+    pub fn tls_cipher(&self) -> Option<&str> {
+        self.tls_cipher.as_deref()
+    }
+
+    /// The identity of the authenticated client
+    pub fn auth_identity(&self) -> Option<&str> {
+        self.auth_identity.as_deref()
+    }
+
+    /// The original client of the current mail transaction, as given by a proxy MTA
+    /// with XFORWARD
+    pub fn forwarded(&self) -> Option<&ForwardedClient> {
+        self.forwarded.as_ref()
+    }
+
+    pub(crate) fn set_ip(&mut self, ip: IpAddr) {
+        self.ip = ip;
+    }
+
+    pub(crate) fn set_client_name(&mut self, name: Option<String>) {
+        self.client_name = name;
+    }
+
+    pub(crate) fn set_helo_domain(&mut self, domain: &str) {
+        self.helo_domain = Some(domain.to_string());
+    }
+
+    pub(crate) fn set_helo(&mut self, domain: Option<String>) {
+        self.helo_domain = domain;
+    }
+
+    pub(crate) fn set_forwarded(&mut self, forwarded: ForwardedClient) {
+        self.forwarded = Some(forwarded);
+    }
+
+    // XFORWARD only applies to one mail transaction
+    pub(crate) fn end_transaction(&mut self) {
+        self.forwarded = None;
+    }
+
+    pub(crate) fn set_tls_cipher(&mut self, cipher: &str) {
+        self.tls_cipher = Some(cipher.to_string());
+    }
+
+    pub(crate) fn set_auth_identity(&mut self, identity: Option<String>) {
+        self.auth_identity = identity;
+    }
+
+    // After STARTTLS the client starts again, forgetting the HELO and authentication
+    pub(crate) fn start_tls(&mut self) {
+        self.tls = true;
+        self.helo_domain = None;
+        self.auth_identity = None;
+    }
+}
+
+/// The attributes of a client given by a proxy MTA with XFORWARD. Attributes that the
+/// proxy did not send, or marked as unavailable, are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardedClient {
+    /// The host name of the original client
+    pub name: Option<String>,
+    /// The IP address of the original client
+    pub addr: Option<IpAddr>,
+    /// The TCP port of the original client
+    pub port: Option<u16>,
+    /// The protocol used by the original client, e.g. `ESMTP`
+    pub proto: Option<String>,
+    /// The HELO domain given by the original client
+    pub helo: Option<String>,
+    /// The queue id of the message at the proxy
+    pub ident: Option<String>,
+    /// `LOCAL` or `REMOTE`, the origin of the message at the proxy
+    pub source: Option<String>,
+}
@@ -9,6 +9,11 @@
 //! messages. After consulting the `Handler` the `Session.process_line()` function will
 //! return a response that can be sent back to the email client.
 //!
+//! Decisions that need I/O can instead be made outside of the `Handler`, by sending
+//! lines to `Session.process_event()`, which returns an `Event` for each decision, and
+//! resuming the session with `Session.resume()` once the decision has been made.
+//! An `AsyncSession` does this for an `AsyncHandler`, whose decisions are futures.
+//!
 //! # Pseudo Code
 //! ```rust,ignore
 //! // Create a handler which will control the SMTP session
@@ -38,17 +43,30 @@
 #![forbid(unsafe_code)]
 #![forbid(missing_docs)]
 
+use std::future::Future;
 use std::io;
-use std::net::IpAddr;
+/// Delivery status notification parameters given by clients (RFC 3461)
+pub mod dsn;
 mod fsm;
+mod info;
+mod params;
 mod parser;
+/// Envelope addresses given with MAIL and RCPT (RFC 5321)
+pub mod path;
+mod received;
 /// Response contains a selection of SMTP responses for use in handlers.
 pub mod response;
+mod sasl;
 mod smtp;
 
 pub use crate::{
-    response::{Action, Response},
-    smtp::{Session, SessionBuilder},
+    dsn::{MailDsn, RcptDsn},
+    info::{ForwardedClient, SessionInfo},
+    params::{EsmtpParams, InvalidXtext},
+    path::{Mailbox, Path},
+    response::{Action, EnhancedStatus, Response, ResponseBuilder},
+    sasl::{SaslExchange, SaslMechanism, SaslStep, ScramCredentials},
+    smtp::{AsyncSession, Session, SessionBuilder},
 };
 
 /// A `Handler` makes decisions about incoming mail commands.
@@ -58,15 +76,17 @@ pub use crate::{
 /// All methods have a default implementation that does nothing. A separate handler instance
 /// should be created for each connection.
 ///
+/// Every method is given the `SessionInfo` of the session, which holds the client IP
+/// address, the HELO domain, the TLS state and the authenticated identity.
+///
 /// # Examples
 /// ```
-/// # use mailin::{Handler, Response};
-/// # use mailin::response::{OK, BAD_HELLO, NO_MAILBOX};
+/// # use mailin::{EsmtpParams, Handler, MailDsn, Path, RcptDsn, Response, SessionInfo};
+/// # use mailin::response::{OK, BAD_HELLO, BAD_MAILBOX, NO_MAILBOX};
 ///
-/// # use std::net::IpAddr;
 /// # struct MyHandler{};
 /// impl Handler for MyHandler {
-///     fn helo(&mut self, ip: IpAddr, domain: &str) -> Response {
+///     fn helo(&mut self, _info: &SessionInfo, domain: &str) -> Response {
 ///        if domain == "this.is.spam.com" {
 ///            OK
 ///        } else {
@@ -74,55 +94,166 @@ pub use crate::{
 ///        }
 ///     }
 ///
-///     fn rcpt(&mut self, to: &str) -> Response {
-///        if to == "alienscience" {
-///            OK
-///        } else {
-///            NO_MAILBOX
+///     fn mail(
+///         &mut self,
+///         info: &SessionInfo,
+///         from: &Path,
+///         _dsn: &MailDsn,
+///         _params: &EsmtpParams,
+///     ) -> Response {
+///        // Authenticated users may only send as themselves
+///        match info.auth_identity() {
+///            Some(user) if from.to_string() != user => BAD_MAILBOX,
+///            _ => OK,
+///        }
+///     }
+///
+///     fn rcpt(
+///         &mut self,
+///         _info: &SessionInfo,
+///         to: &Path,
+///         _dsn: &RcptDsn,
+///         _params: &EsmtpParams,
+///     ) -> Response {
+///        match to.mailbox() {
+///            Some(mailbox) if mailbox.local_part == "alienscience" => OK,
+///            _ => NO_MAILBOX,
 ///        }
 ///     }
 /// }
 /// ```
 pub trait Handler {
+    /// Called when a client connects, before the greeting is sent.
+    ///
+    /// Return an error response, such as `response::NO_SMTP_SERVICE`, to refuse the
+    /// connection. The response is sent instead of the greeting and the connection is
+    /// closed.
+    fn connect(&mut self, _info: &SessionInfo) -> Response {
+        response::OK
+    }
+
     /// Called when a client sends a ehlo or helo message
-    fn helo(&mut self, _ip: IpAddr, _domain: &str) -> Response {
+    fn helo(&mut self, _info: &SessionInfo, _domain: &str) -> Response {
         response::OK
     }
 
+    /// Extra keywords to advertise in the response to EHLO or LHLO, e.g. `"XDIAG"` or
+    /// `"X-FEATURE 1"`. Commands for the extension are passed to `unknown_command` and
+    /// MAIL or RCPT parameters must be enabled with `SessionBuilder::enable_esmtp_param`.
+    fn ehlo_keywords(&mut self, _info: &SessionInfo) -> Vec<String> {
+        Vec::new()
+    }
+
     /// Called when a mail message is started
-    fn mail(&mut self, _ip: IpAddr, _domain: &str, _from: &str) -> Response {
+    ///
+    /// `dsn` holds the delivery status notification parameters given by the client and
+    /// `params` holds all the ESMTP parameters given by the client.
+    fn mail(
+        &mut self,
+        _info: &SessionInfo,
+        _from: &Path,
+        _dsn: &MailDsn,
+        _params: &EsmtpParams,
+    ) -> Response {
         response::OK
     }
 
     /// Called when a mail recipient is set
-    fn rcpt(&mut self, _to: &str) -> Response {
+    ///
+    /// `dsn` holds the delivery status notification parameters given by the client and
+    /// `params` holds all the ESMTP parameters given by the client.
+    fn rcpt(
+        &mut self,
+        _info: &SessionInfo,
+        _to: &Path,
+        _dsn: &RcptDsn,
+        _params: &EsmtpParams,
+    ) -> Response {
         response::OK
     }
 
     /// Called when a data command is received
+    ///
+    /// `smtputf8` is set when the client declared that the envelope or headers can
+    /// contain UTF-8 (RFC 6531). `require_tls` is set when the client asked, with
+    /// REQUIRETLS, that the message is only relayed over verified TLS (RFC 8689).
     fn data_start(
         &mut self,
-        _domain: &str,
-        _from: &str,
+        _info: &SessionInfo,
+        _from: &Path,
         _is8bit: bool,
-        _to: &[String],
+        _smtputf8: bool,
+        _require_tls: bool,
+        _to: &[Path],
     ) -> Response {
         response::OK
     }
 
     /// Called when a data buffer is received
-    fn data(&mut self, _buf: &[u8]) -> io::Result<()> {
+    fn data(&mut self, _info: &SessionInfo, _buf: &[u8]) -> io::Result<()> {
         Ok(())
     }
 
     /// Called at the end of receiving data
-    fn data_end(&mut self) -> Response {
+    fn data_end(&mut self, _info: &SessionInfo) -> Response {
         response::OK
     }
 
+    /// Called at the end of receiving data in an LMTP session.
+    ///
+    /// Returns one response for each recipient, in the order given. Missing responses
+    /// repeat the last one, or are `response::INTERNAL_ERROR` if there are none, and
+    /// extra responses are dropped. The default implementation returns the response
+    /// from `data_end` for every recipient.
+    fn data_end_lmtp(&mut self, info: &SessionInfo, to: &[Path]) -> Vec<Response> {
+        let res = self.data_end(info);
+        vec![res; to.len()]
+    }
+
+    /// Called when a client asks to verify a user or mailbox with VRFY. `arg` is the
+    /// argument as sent by the client, e.g. `"Smith"` or `"<smith@example.com>"`.
+    ///
+    /// The default implementation neither confirms nor denies that the mailbox exists.
+    fn vrfy(&mut self, _info: &SessionInfo, _arg: &str) -> Response {
+        response::VERIFY_RESPONSE
+    }
+
+    /// Called when a client asks for the members of a mailing list with EXPN. A reply
+    /// with one member per line can be made with `Response::builder`.
+    ///
+    /// The default implementation does not reveal the members.
+    fn expn(&mut self, _info: &SessionInfo, _list: &str) -> Response {
+        response::VERIFY_RESPONSE
+    }
+
+    /// Called with a command that mailin does not implement. `verb` is the command as
+    /// sent by the client and `args` is the rest of the line, without the line ending.
+    ///
+    /// The session stays in the same state, whatever the response.
+    fn unknown_command(&mut self, _info: &SessionInfo, _verb: &str, _args: &str) -> Response {
+        response::UNKNOWN_COMMAND
+    }
+
+    /// Called when the client resets the session with RSET, or a trusted proxy restarts
+    /// it with XCLIENT. Any mail transaction in progress is aborted.
+    ///
+    /// Also called when the server aborts a transaction after `data_start` accepted it,
+    /// e.g. because the message is too large or breaks the line policy.
+    fn rset(&mut self, _info: &SessionInfo) {}
+
+    /// Called when the client ends the session with QUIT
+    fn quit(&mut self, _info: &SessionInfo) {}
+
+    /// Called when the connection to the client is closed. Any mail transaction in
+    /// progress is aborted.
+    ///
+    /// The I/O code using the library reports the disconnect with `Session::disconnect`.
+    fn disconnect(&mut self, _info: &SessionInfo, _reason: DisconnectReason) {}
+
     /// Called when a plain authentication request is received
     fn auth_plain(
         &mut self,
+        _info: &SessionInfo,
         _authorization_id: &str,
         _authentication_id: &str,
         _password: &str,
@@ -131,9 +262,254 @@ pub trait Handler {
     }
 
     /// Called when a login authentication request is received
-    fn auth_login(&mut self, _username: &str, _password: &str) -> Response {
+    fn auth_login(&mut self, _info: &SessionInfo, _username: &str, _password: &str) -> Response {
         response::INVALID_CREDENTIALS
     }
+
+    /// Called when a client authenticates with an OAuth 2.0 bearer token, using
+    /// OAUTHBEARER or XOAUTH2.
+    ///
+    /// The user is empty if an OAUTHBEARER client did not give one.
+    fn auth_bearer(&mut self, _info: &SessionInfo, _user: &str, _token: &str) -> Response {
+        response::INVALID_CREDENTIALS
+    }
+
+    /// Called when a client authenticates with AUTH EXTERNAL using its TLS client certificate.
+    ///
+    /// The certificates are DER encoded, starting with the client certificate, and were
+    /// verified by the TLS layer. The authorization identity is empty if the client did
+    /// not request one, in which case it should be derived from the certificate subject
+    /// or subject alternative name.
+    ///
+    /// Returns the identity of the authenticated client, or the response to send if
+    /// authentication failed.
+    fn auth_external(
+        &mut self,
+        _info: &SessionInfo,
+        _authorization_id: &str,
+        _certificates: &[Vec<u8>],
+    ) -> Result<String, Response> {
+        Err(response::INVALID_CREDENTIALS)
+    }
+
+    /// Called when a client authenticated with a `SaslMechanism` registered on the
+    /// `SessionBuilder`.
+    ///
+    /// The default implementation accepts the identity reported by the mechanism.
+    fn auth_sasl(&mut self, _info: &SessionInfo, _mechanism: &str, _identity: &str) -> Response {
+        response::AUTH_OK
+    }
+
+    /// Called to get the shared secret of a user authenticating with CRAM-MD5.
+    ///
+    /// Return `None` if the user is unknown.
+    fn auth_cram_md5_secret(&mut self, _info: &SessionInfo, _username: &str) -> Option<String> {
+        None
+    }
+
+    /// Called to get the stored credentials of a user authenticating with SCRAM-SHA-256.
+    ///
+    /// Return `None` if the user is unknown. The exchange then carries on with made up
+    /// credentials and fails at the end, as it would for a wrong password.
+    fn auth_scram_sha256_credentials(
+        &mut self,
+        _info: &SessionInfo,
+        _username: &str,
+    ) -> Option<ScramCredentials> {
+        None
+    }
+}
+
+/// An `AsyncHandler` makes the decisions about incoming mail commands that can need I/O,
+/// such as DNS or database lookups, without blocking. It is used by an `AsyncSession`,
+/// which is built with `SessionBuilder::build_async`.
+///
+/// The default implementations return the decision of the `Handler`, which is still
+/// used for the other callbacks, e.g. `Handler::data`. The methods are named after the
+/// `Handler` methods they replace, with an `_async` suffix.
+///
+/// # Examples
+/// ```
+/// # use mailin::{AsyncHandler, EsmtpParams, Handler, Path, RcptDsn, SessionInfo, Response};
+/// # use mailin::response::{OK, NO_MAILBOX};
+/// # use std::future::Future;
+/// # async fn lookup(mailbox: String) -> bool { true }
+/// struct MyHandler {}
+/// impl Handler for MyHandler {}
+/// impl AsyncHandler for MyHandler {
+///     fn rcpt_async(
+///         &mut self,
+///         _info: &SessionInfo,
+///         to: &Path,
+///         _dsn: &RcptDsn,
+///         _params: &EsmtpParams,
+///     ) -> impl Future<Output = Response> + Send {
+///         let mailbox = to.to_string();
+///         async move {
+///             if lookup(mailbox).await {
+///                 OK
+///             } else {
+///                 NO_MAILBOX
+///             }
+///         }
+///     }
+/// }
+/// ```
+pub trait AsyncHandler: Handler {
+    /// Called when a client sends a ehlo or helo message, as `Handler::helo`
+    fn helo_async(
+        &mut self,
+        info: &SessionInfo,
+        domain: &str,
+    ) -> impl Future<Output = Response> + Send {
+        let res = Handler::helo(self, info, domain);
+        async move { res }
+    }
+
+    /// Called when a mail message is started, as `Handler::mail`
+    fn mail_async(
+        &mut self,
+        info: &SessionInfo,
+        from: &Path,
+        dsn: &MailDsn,
+        params: &EsmtpParams,
+    ) -> impl Future<Output = Response> + Send {
+        let res = Handler::mail(self, info, from, dsn, params);
+        async move { res }
+    }
+
+    /// Called when a mail recipient is set, as `Handler::rcpt`
+    fn rcpt_async(
+        &mut self,
+        info: &SessionInfo,
+        to: &Path,
+        dsn: &RcptDsn,
+        params: &EsmtpParams,
+    ) -> impl Future<Output = Response> + Send {
+        let res = Handler::rcpt(self, info, to, dsn, params);
+        async move { res }
+    }
+
+    /// Called when a data command is received, as `Handler::data_start`
+    fn data_start_async(
+        &mut self,
+        info: &SessionInfo,
+        from: &Path,
+        is8bit: bool,
+        smtputf8: bool,
+        require_tls: bool,
+        to: &[Path],
+    ) -> impl Future<Output = Response> + Send {
+        let res = Handler::data_start(self, info, from, is8bit, smtputf8, require_tls, to);
+        async move { res }
+    }
+
+    /// Called at the end of receiving data, as `Handler::data_end`. Not called in an
+    /// LMTP session, where `data_end_lmtp_async` is called instead.
+    fn data_end_async(&mut self, info: &SessionInfo) -> impl Future<Output = Response> + Send {
+        let res = Handler::data_end(self, info);
+        async move { res }
+    }
+
+    /// Called at the end of receiving data in an LMTP session, as
+    /// `Handler::data_end_lmtp`
+    fn data_end_lmtp_async(
+        &mut self,
+        info: &SessionInfo,
+        to: &[Path],
+    ) -> impl Future<Output = Vec<Response>> + Send {
+        let res = Handler::data_end_lmtp(self, info, to);
+        async move { res }
+    }
+}
+
+/// How strictly a session checks the lines sent by clients.
+///
+/// A bare CR or LF, that is not part of a CRLF line ending, can be used to smuggle
+/// commands past servers that disagree on where a message ends. Very long lines are
+/// used to exhaust memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinePolicy {
+    /// Accept bare CR and LF, and lines of any length
+    Lenient,
+    /// Accept commands that end with a bare LF and convert bare CR and LF in messages
+    /// to CRLF. Messages with a bare CR or LF followed by a dot, which could hide the
+    /// end of data, and lines longer than the RFC 5321 limits are rejected.
+    #[default]
+    Normalize,
+    /// Reject commands and messages that contain a bare CR or LF. Lines longer than the
+    /// RFC 5321 limits are rejected. The connection is closed if the end of data follows
+    /// a bare CR or LF.
+    Strict,
+}
+
+/// Why the connection to a client was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client ended the session with QUIT
+    Quit,
+    /// The server closed the connection after an error response
+    Closed,
+    /// The client closed the connection without QUIT
+    Eof,
+    /// The client did not send anything for too long
+    Timeout,
+    /// The connection failed with an I/O or TLS error
+    Error,
+}
+
+/// The result of `Session::process_event` and `Session::resume`: either a response
+/// for the client, or a decision that the session is waiting for.
+///
+/// A decision is made with the `Response` that the matching `Handler` method would
+/// return, and is given to `Session::resume`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A response that should be written back to the client
+    Response(Response),
+    /// Accept or reject the domain given with HELO, EHLO or LHLO, as `Handler::helo`
+    NeedHeloDecision {
+        /// The domain given by the client
+        domain: String,
+    },
+    /// Accept or reject the sender of a message, as `Handler::mail`
+    NeedMailDecision {
+        /// The reverse path given with MAIL
+        from: Path,
+        /// The DSN parameters given with MAIL
+        dsn: MailDsn,
+        /// All the ESMTP parameters given with MAIL
+        params: EsmtpParams,
+    },
+    /// Accept or reject a recipient, as `Handler::rcpt`
+    NeedRcptDecision {
+        /// The forward path given with RCPT
+        to: Path,
+        /// The DSN parameters given with RCPT
+        dsn: RcptDsn,
+        /// All the ESMTP parameters given with RCPT
+        params: EsmtpParams,
+    },
+    /// Accept or reject a message before its data is sent, as `Handler::data_start`
+    NeedDataStartDecision {
+        /// The reverse path of the message
+        from: Path,
+        /// The message has an 8BITMIME or BINARYMIME body
+        is8bit: bool,
+        /// The envelope or headers can contain UTF-8
+        smtputf8: bool,
+        /// The message must only be relayed over verified TLS
+        require_tls: bool,
+        /// The accepted recipients of the message
+        to: Vec<Path>,
+    },
+    /// Accept or reject a message once all of its data has been received, as
+    /// `Handler::data_end`. In LMTP a single response is given for every recipient,
+    /// `Session::resume_lmtp` gives one response per recipient instead.
+    NeedDataEndDecision {
+        /// The accepted recipients of the message
+        to: Vec<Path>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -144,6 +520,22 @@ pub enum AuthMechanism {
 
     /// Sequential mechanism over TLS
     Login,
+
+    /// Challenge-response with a shared secret (RFC 2195)
+    CramMd5,
+
+    /// Salted challenge-response (RFC 7677)
+    ScramSha256,
+
+    /// OAuth 2.0 bearer token over TLS (RFC 7628)
+    OAuthBearer,
+
+    /// OAuth 2.0 bearer token over TLS, using the older XOAUTH2 format
+    XOAuth2,
+
+    /// TLS client certificate (RFC 4422 appendix A).
+    /// Only offered once the client has presented a certificate.
+    External,
 }
 
 impl AuthMechanism {
@@ -152,8 +544,24 @@ impl AuthMechanism {
         match self {
             AuthMechanism::Plain => "PLAIN",
             AuthMechanism::Login => "LOGIN",
+            AuthMechanism::CramMd5 => "CRAM-MD5",
+            AuthMechanism::ScramSha256 => "SCRAM-SHA-256",
+            AuthMechanism::OAuthBearer => "OAUTHBEARER",
+            AuthMechanism::XOAuth2 => "XOAUTH2",
+            AuthMechanism::External => "EXTERNAL",
         }
     }
+
+    // Does the mechanism send the password or token in the clear?
+    fn is_plaintext(&self) -> bool {
+        matches!(
+            self,
+            AuthMechanism::Plain
+                | AuthMechanism::Login
+                | AuthMechanism::OAuthBearer
+                | AuthMechanism::XOAuth2
+        )
+    }
 }
 
 #[cfg(test)]
@@ -161,7 +569,7 @@ mod tests {
     use super::*;
     use crate::response::*;
     use std::io::{Cursor, Write};
-    use std::net::Ipv4Addr;
+    use std::net::{IpAddr, Ipv4Addr};
 
     struct TestHandler {
         ip: IpAddr,
@@ -169,6 +577,7 @@ mod tests {
         from: String,
         to: Vec<String>,
         is8bit: bool,
+        smtputf8: bool,
         expected_data: Vec<u8>,
         cursor: Cursor<Vec<u8>>,
         // Booleans set when callbacks are successful
@@ -180,26 +589,38 @@ mod tests {
         data_end_called: bool,
     }
 
-    impl<'a> Handler for &'a mut TestHandler {
-        fn helo(&mut self, ip: IpAddr, domain: &str) -> Response {
-            assert_eq!(self.ip, ip);
+    impl Handler for &mut TestHandler {
+        fn helo(&mut self, info: &SessionInfo, domain: &str) -> Response {
+            assert_eq!(self.ip, info.ip());
             assert_eq!(self.domain, domain);
             self.helo_called = true;
             OK
         }
 
         // Called when a mail message is started
-        fn mail(&mut self, ip: IpAddr, domain: &str, from: &str) -> Response {
-            assert_eq!(self.ip, ip);
-            assert_eq!(self.domain, domain);
-            assert_eq!(self.from, from);
+        fn mail(
+            &mut self,
+            info: &SessionInfo,
+            from: &Path,
+            _dsn: &MailDsn,
+            _params: &EsmtpParams,
+        ) -> Response {
+            assert_eq!(self.ip, info.ip());
+            assert_eq!(Some(self.domain.as_str()), info.helo_domain());
+            assert_eq!(self.from, from.to_string());
             self.mail_called = true;
             OK
         }
 
         // Called when a mail recipient is set
-        fn rcpt(&mut self, to: &str) -> Response {
-            let valid_to = self.to.iter().any(|elem| elem == to);
+        fn rcpt(
+            &mut self,
+            _info: &SessionInfo,
+            to: &Path,
+            _dsn: &RcptDsn,
+            _params: &EsmtpParams,
+        ) -> Response {
+            let valid_to = self.to.iter().any(|elem| *elem == to.to_string());
             assert!(valid_to, "Invalid to address");
             self.rcpt_called = true;
             OK
@@ -208,25 +629,30 @@ mod tests {
         // Called to start writing an email message to a writer
         fn data_start(
             &mut self,
-            domain: &str,
-            from: &str,
+            info: &SessionInfo,
+            from: &Path,
             is8bit: bool,
-            to: &[String],
+            smtputf8: bool,
+            require_tls: bool,
+            to: &[Path],
         ) -> Response {
-            assert_eq!(self.domain, domain);
-            assert_eq!(self.from, from);
+            assert_eq!(Some(self.domain.as_str()), info.helo_domain());
+            assert_eq!(self.smtputf8, smtputf8);
+            assert!(!require_tls);
+            assert_eq!(self.from, from.to_string());
+            let to: Vec<String> = to.iter().map(Path::to_string).collect();
             assert_eq!(self.to, to);
             assert_eq!(self.is8bit, is8bit);
             self.data_start_called = true;
             OK
         }
 
-        fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        fn data(&mut self, _info: &SessionInfo, buf: &[u8]) -> io::Result<()> {
             self.data_called = true;
             self.cursor.write(buf).map(|_| ())
         }
 
-        fn data_end(&mut self) -> Response {
+        fn data_end(&mut self, _info: &SessionInfo) -> Response {
             self.data_end_called = true;
             let actual_data = self.cursor.get_ref();
             assert_eq!(actual_data, &self.expected_data);
@@ -254,6 +680,7 @@ mod tests {
             from: from.to_owned(),
             to: to.clone(),
             is8bit: true,
+            smtputf8: false,
             expected_data,
             cursor: Cursor::new(Vec::with_capacity(80)),
             helo_called: false,
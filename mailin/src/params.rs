@@ -0,0 +1,68 @@
+use crate::parser::decode_xtext;
+
+/// The ESMTP parameters that follow the path in a MAIL or RCPT command.
+///
+/// Keywords are case insensitive and are stored in upper case. Values are stored as sent
+/// by the client.
+///
+/// # Examples
+/// ```
+/// # use mailin::EsmtpParams;
+/// let mut params = EsmtpParams::default();
+/// params.push("mt-priority", Some("3"));
+/// params.push("X-TAG", Some("a+2Bb"));
+/// params.push("SMTPUTF8", None);
+///
+/// assert_eq!(params.value("MT-PRIORITY"), Some("3"));
+/// assert_eq!(params.xtext("x-tag"), Some(Ok("a+b".to_string())));
+/// assert!(params.contains("smtputf8"));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EsmtpParams(Vec<(String, Option<String>)>);
+
+impl EsmtpParams {
+    /// Add a parameter
+    pub fn push<K: Into<String>>(&mut self, keyword: K, value: Option<&str>) {
+        let keyword = keyword.into().to_ascii_uppercase();
+        self.0.push((keyword, value.map(str::to_owned)));
+    }
+
+    /// Was the parameter given?
+    pub fn contains(&self, keyword: &str) -> bool {
+        self.get(keyword).is_some()
+    }
+
+    /// Get a parameter, returns `Some(None)` if the parameter was given without a value
+    pub fn get(&self, keyword: &str) -> Option<Option<&str>> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(keyword))
+            .map(|(_, v)| v.as_deref())
+    }
+
+    /// Get the value of a parameter
+    pub fn value(&self, keyword: &str) -> Option<&str> {
+        self.get(keyword).flatten()
+    }
+
+    /// Get the value of a parameter decoded from xtext (RFC 3461).
+    /// Returns an error if the value is not valid xtext.
+    pub fn xtext(&self, keyword: &str) -> Option<Result<String, InvalidXtext>> {
+        self.value(keyword)
+            .map(|v| decode_xtext(v.as_bytes()).map_err(|_| InvalidXtext))
+    }
+
+    /// Iterate over the keywords and values of all parameters
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_deref()))
+    }
+
+    /// Are there no parameters?
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Error returned when a parameter value is not valid xtext
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidXtext;
@@ -1,22 +1,32 @@
 use nom::branch::alt;
-use nom::bytes::complete::{is_not, tag, tag_no_case, take_while1};
+use nom::bytes::complete::{is_not, tag, tag_no_case, take_while, take_while1, take_while_m_n};
+use nom::character::complete::digit1;
 use nom::character::is_alphanumeric;
-use nom::combinator::{map, map_res, value};
-use nom::sequence::{pair, preceded, separated_pair, terminated};
+use nom::combinator::{map, map_res, opt, peek, recognize, value, verify};
+use nom::multi::{fold_many0, separated_list1};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated};
 use nom::IResult;
 
+use crate::dsn::{MailDsn, Notify, OriginalRecipient, RcptDsn, Ret};
+use crate::path::{Host, Mailbox, Path};
 use crate::response::*;
-use crate::smtp::{Cmd, Credentials};
+use crate::sasl::{ScramClientFinal, ScramClientFirst};
+use crate::smtp::{BodyType, Cmd, Credentials};
+use crate::EsmtpParams;
+use std::net::{IpAddr, Ipv6Addr};
 use std::str;
 
 //----- Parser -----------------------------------------------------------------
 
 // Parse a line from the client
-pub fn parse(line: &[u8]) -> Result<Cmd, Response> {
-    command(line).map(|r| r.1).map_err(|e| match e {
-        nom::Err::Incomplete(_) => MISSING_PARAMETER,
-        nom::Err::Error(_) => SYNTAX_ERROR,
-        nom::Err::Failure(_) => SYNTAX_ERROR,
+pub fn parse(line: &[u8]) -> Result<Cmd<'_>, Response> {
+    command(line).map(|r| r.1).or_else(|e| match e {
+        nom::Err::Incomplete(_) => Err(MISSING_PARAMETER),
+        nom::Err::Error(_) | nom::Err::Failure(_) if bad_params(line).is_ok() => Err(BAD_PARAMETER),
+        // A bad command that mailin implements is a syntax error
+        nom::Err::Error(_) | nom::Err::Failure(_) => {
+            unknown_command(line).map(|r| r.1).map_err(|_| SYNTAX_ERROR)
+        }
     })
 }
 
@@ -25,89 +35,377 @@ pub fn parse_auth_response(line: &[u8]) -> Result<&[u8], Response> {
     auth_response(line).map(|r| r.1).map_err(|_| SYNTAX_ERROR)
 }
 
-fn command(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn command(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     terminated(
         alt((
-            helo, ehlo, mail, rcpt, data, rset, quit, vrfy, noop, starttls, auth,
+            helo, ehlo, lhlo, mail, rcpt, data, bdat, rset, quit, vrfy, expn, noop, starttls, auth,
+            xclient, xforward,
         )),
         tag(b"\r\n"),
     )(buf)
 }
 
+// The verbs of the commands that mailin implements
+const VERBS: &[&str] = &[
+    "helo", "ehlo", "lhlo", "mail", "rcpt", "data", "bdat", "rset", "quit", "vrfy", "expn", "noop",
+    "starttls", "auth", "xclient", "xforward",
+];
+
+// Match a command that mailin does not implement, the arguments are passed on as they are
+fn unknown_command(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let verb = verify(
+        map_res(
+            take_while1(|c: u8| is_alphanumeric(c) || c == b'-'),
+            str::from_utf8,
+        ),
+        |verb: &str| !VERBS.iter().any(|v| v.eq_ignore_ascii_case(verb)),
+    );
+    let args = map_res(take_while(|c| c != b'\r' && c != b'\n'), str::from_utf8);
+    let parse_command = terminated(pair(verb, opt(preceded(space, args))), tag(b"\r\n"));
+    map(parse_command, |(verb, args)| Cmd::Unknown {
+        verb,
+        args: args.unwrap_or_default(),
+    })(buf)
+}
+
 fn hello_domain(buf: &[u8]) -> IResult<&[u8], &str> {
     map_res(is_not(b" \t\r\n" as &[u8]), str::from_utf8)(buf)
 }
 
-fn helo(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn helo(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     let parse_domain = preceded(cmd(b"helo"), hello_domain);
     map(parse_domain, |domain| Cmd::Helo { domain })(buf)
 }
 
-fn ehlo(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn ehlo(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     let parse_domain = preceded(cmd(b"ehlo"), hello_domain);
     map(parse_domain, |domain| Cmd::Ehlo { domain })(buf)
 }
 
-fn mail_path(buf: &[u8]) -> IResult<&[u8], &str> {
-    map_res(is_not(b" <>\t\r\n" as &[u8]), str::from_utf8)(buf)
+fn lhlo(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parse_domain = preceded(cmd(b"lhlo"), hello_domain);
+    map(parse_domain, |domain| Cmd::Lhlo { domain })(buf)
+}
+
+// Match a character of an atom, UTF-8 is allowed for SMTPUTF8
+pub(crate) fn is_atext(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-/=?^_`{|}~".contains(&c) || c >= 0x80
+}
+
+// Match a letter, digit or UTF-8 character of a domain label
+fn is_let_dig(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c >= 0x80
+}
+
+fn to_string(buf: &[u8]) -> Result<String, str::Utf8Error> {
+    str::from_utf8(buf).map(str::to_owned)
+}
+
+fn dot_string(buf: &[u8]) -> IResult<&[u8], String> {
+    let atoms = separated_list1(tag(b"."), take_while1(is_atext));
+    map_res(recognize(atoms), to_string)(buf)
+}
+
+// Match a character of a quoted string that does not need escaping
+fn is_qtext(c: u8) -> bool {
+    matches!(c, b' ' | b'!' | b'#'..=b'[' | b']'..=b'~') || c >= 0x80
+}
+
+// Match a quoted string and return its content without the quoted-pair escapes
+fn quoted_string(buf: &[u8]) -> IResult<&[u8], String> {
+    let qtext = take_while1(is_qtext);
+    let quoted_pair = preceded(
+        tag(b"\\"),
+        take_while_m_n(1, 1, |c| (b' '..=b'~').contains(&c)),
+    );
+    let content = fold_many0(alt((qtext, quoted_pair)), Vec::new, |mut content, s| {
+        content.extend_from_slice(s);
+        content
+    });
+    map_res(
+        delimited(tag(b"\""), content, tag(b"\"")),
+        String::from_utf8,
+    )(buf)
+}
+
+// Match a domain, UTF-8 labels are allowed for SMTPUTF8
+fn domain(buf: &[u8]) -> IResult<&[u8], String> {
+    let label = verify(take_while1(|c| is_let_dig(c) || c == b'-'), |l: &[u8]| {
+        l[0] != b'-' && l[l.len() - 1] != b'-'
+    });
+    map_res(recognize(separated_list1(tag(b"."), label)), to_string)(buf)
+}
+
+// Interpret the content of an address literal
+fn address_literal(literal: &[u8]) -> Result<Host, ()> {
+    let literal = str::from_utf8(literal).map_err(|_| ())?;
+    match literal.split_once(':') {
+        Some((tag, ip)) if tag.eq_ignore_ascii_case("IPv6") => ip
+            .parse::<Ipv6Addr>()
+            .map(|ip| Host::Address(IpAddr::V6(ip)))
+            .map_err(|_| ()),
+        // A general address literal has a standardized tag, which is an Ldh-str
+        Some((tag, content))
+            if !content.is_empty()
+                && tag.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && !tag.ends_with('-')
+                && tag.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-') =>
+        {
+            Ok(Host::Literal {
+                tag: tag.to_owned(),
+                content: content.to_owned(),
+            })
+        }
+        Some(_) => Err(()),
+        None => literal
+            .parse()
+            .map(|ip| Host::Address(IpAddr::V4(ip)))
+            .map_err(|_| ()),
+    }
+}
+
+fn host(buf: &[u8]) -> IResult<&[u8], Host> {
+    let literal = delimited(
+        tag(b"["),
+        take_while1(|c| (b'!'..=b'~').contains(&c) && c != b'[' && c != b']' && c != b'\\'),
+        tag(b"]"),
+    );
+    alt((map_res(literal, address_literal), map(domain, Host::Domain)))(buf)
+}
+
+fn mailbox(buf: &[u8]) -> IResult<&[u8], Mailbox> {
+    let local_part = alt((dot_string, quoted_string));
+    let parser = separated_pair(local_part, tag(b"@"), host);
+    map(parser, |(local_part, domain)| Mailbox {
+        local_part,
+        domain,
+    })(buf)
+}
+
+// A mailbox in angle brackets. The obsolete source route is ignored (RFC 5321 section 4.1.2).
+fn path(buf: &[u8]) -> IResult<&[u8], Mailbox> {
+    let at_domain = preceded(tag(b"@"), domain);
+    let source_route = terminated(separated_list1(tag(b","), at_domain), tag(b":"));
+    delimited(tag(b"<"), preceded(opt(source_route), mailbox), tag(b">"))(buf)
+}
+
+fn reverse_path(buf: &[u8]) -> IResult<&[u8], Path> {
+    alt((value(Path::Null, tag(b"<>")), map(path, Path::Mailbox)))(buf)
+}
+
+fn forward_path(buf: &[u8]) -> IResult<&[u8], Path> {
+    let postmaster = value(Path::Postmaster, tag_no_case(b"<postmaster>"));
+    alt((postmaster, map(path, Path::Mailbox)))(buf)
 }
 
 fn take_all(buf: &[u8]) -> IResult<&[u8], &str> {
     map_res(is_not(b"\r\n" as &[u8]), str::from_utf8)(buf)
 }
 
-fn body_eq_8bit(buf: &[u8]) -> IResult<&[u8], bool> {
-    let preamble = pair(space, tag_no_case(b"body="));
-    let is8bit = alt((
-        value(true, tag_no_case(b"8bitmime")),
-        value(false, tag_no_case(b"7bit")),
+// The parameters of a MAIL command that are handled by mailin
+#[derive(Default)]
+struct MailParams {
+    body: BodyType,
+    size: Option<usize>,
+    smtputf8: bool,
+    require_tls: bool,
+    dsn: MailDsn,
+}
+
+// Keywords of the MAIL parameters that are handled by mailin
+pub(crate) const MAIL_KEYWORDS: &[&str] = &[
+    "BODY",
+    "SIZE",
+    "SMTPUTF8",
+    "REQUIRETLS",
+    "RET",
+    "ENVID",
+    "AUTH",
+];
+
+// Keywords of the RCPT parameters that are handled by mailin
+pub(crate) const RCPT_KEYWORDS: &[&str] = &["NOTIFY", "ORCPT"];
+
+// Match an esmtp-keyword
+fn esmtp_keyword(buf: &[u8]) -> IResult<&[u8], &str> {
+    let keyword = recognize(pair(
+        take_while_m_n(1, 1, is_alphanumeric),
+        take_while(|c| is_alphanumeric(c) || c == b'-'),
     ));
-    preceded(preamble, is8bit)(buf)
+    map_res(keyword, str::from_utf8)(buf)
+}
+
+// Match an esmtp-value, UTF-8 is allowed for SMTPUTF8
+fn esmtp_value(buf: &[u8]) -> IResult<&[u8], &str> {
+    let value = take_while1(|c| c > b' ' && c != b'=' && c != 127);
+    map_res(value, str::from_utf8)(buf)
+}
+
+fn esmtp_params(buf: &[u8]) -> IResult<&[u8], EsmtpParams> {
+    let param = pair(esmtp_keyword, opt(preceded(tag(b"="), esmtp_value)));
+    fold_many0(
+        preceded(space, param),
+        EsmtpParams::default,
+        |mut params, (keyword, value)| {
+            params.push(keyword, value);
+            params
+        },
+    )(buf)
 }
 
-fn is8bitmime(buf: &[u8]) -> IResult<&[u8], bool> {
-    body_eq_8bit(buf).or(Ok((buf, false)))
+// Match a MAIL or RCPT command that is well formed, so that it can only have failed
+// because of the values of its parameters
+fn bad_params(buf: &[u8]) -> IResult<&[u8], EsmtpParams> {
+    let mail = preceded(pair(cmd(b"mail"), tag_no_case(b"from:")), reverse_path);
+    let rcpt = preceded(pair(cmd(b"rcpt"), tag_no_case(b"to:")), forward_path);
+    terminated(preceded(alt((mail, rcpt)), esmtp_params), tag(b"\r\n"))(buf)
 }
 
-fn mail(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let preamble = pair(cmd(b"mail"), tag_no_case(b"from:<"));
-    let mail_path_parser = preceded(preamble, mail_path);
-    let parser = separated_pair(mail_path_parser, tag(b">"), is8bitmime);
-    map(parser, |r| Cmd::Mail {
-        reverse_path: r.0,
-        is8bit: r.1,
+// Is a parameter keyword given more than once?
+fn has_repeated_keyword(params: &EsmtpParams) -> bool {
+    let keywords: Vec<&str> = params.iter().map(|(keyword, _)| keyword).collect();
+    (1..keywords.len()).any(|i| keywords[..i].contains(&keywords[i]))
+}
+
+// Interpret the MAIL parameters handled by mailin
+fn mail_params(params: &EsmtpParams) -> Result<MailParams, ()> {
+    if has_repeated_keyword(params) {
+        return Err(());
+    }
+    let mut ret = MailParams::default();
+    for (keyword, value) in params.iter() {
+        match (keyword, value) {
+            ("BODY", Some(body)) => {
+                ret.body = match body.to_ascii_uppercase().as_str() {
+                    "7BIT" => BodyType::SevenBit,
+                    "8BITMIME" => BodyType::EightBitMime,
+                    "BINARYMIME" => BodyType::BinaryMime,
+                    _ => return Err(()),
+                }
+            }
+            ("SIZE", Some(size)) => ret.size = Some(size.parse().map_err(|_| ())?),
+            ("SMTPUTF8", None) => ret.smtputf8 = true,
+            ("REQUIRETLS", None) => ret.require_tls = true,
+            ("RET", Some(r)) => {
+                ret.dsn.ret = match r.to_ascii_uppercase().as_str() {
+                    "FULL" => Some(Ret::Full),
+                    "HDRS" => Some(Ret::Headers),
+                    _ => return Err(()),
+                }
+            }
+            ("ENVID", Some(envid)) => ret.dsn.envid = Some(decode_xtext(envid.as_bytes())?),
+            ("AUTH", Some(_)) => (),
+            (k, _) if MAIL_KEYWORDS.contains(&k) => return Err(()),
+            _ => (),
+        }
+    }
+    Ok(ret)
+}
+
+// Interpret the RCPT parameters handled by mailin
+fn rcpt_params(params: &EsmtpParams) -> Result<RcptDsn, ()> {
+    if has_repeated_keyword(params) {
+        return Err(());
+    }
+    let mut dsn = RcptDsn::default();
+    for (keyword, value) in params.iter() {
+        match (keyword, value) {
+            ("NOTIFY", Some(notify)) => dsn.notify = Some(decode_notify(notify.as_bytes())?),
+            ("ORCPT", Some(orcpt)) => {
+                let (addr_type, address) = orcpt.split_once(';').ok_or(())?;
+                dsn.orcpt = Some(OriginalRecipient {
+                    addr_type: addr_type.to_owned(),
+                    address: decode_xtext(address.as_bytes())?,
+                });
+            }
+            (k, _) if RCPT_KEYWORDS.contains(&k) => return Err(()),
+            _ => (),
+        }
+    }
+    Ok(dsn)
+}
+
+fn mail(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let preamble = pair(cmd(b"mail"), tag_no_case(b"from:"));
+    let parser = pair(preceded(preamble, reverse_path), esmtp_params);
+    map_res(parser, |(reverse_path, params)| {
+        mail_params(&params).map(|mail| Cmd::Mail {
+            reverse_path,
+            body: mail.body,
+            size: mail.size,
+            smtputf8: mail.smtputf8,
+            require_tls: mail.require_tls,
+            dsn: mail.dsn,
+            params,
+        })
     })(buf)
 }
 
-fn rcpt(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let preamble = pair(cmd(b"rcpt"), tag_no_case(b"to:<"));
-    let mail_path_parser = preceded(preamble, mail_path);
-    let parser = terminated(mail_path_parser, tag(b">"));
-    map(parser, |path| Cmd::Rcpt { forward_path: path })(buf)
+fn rcpt(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let preamble = pair(cmd(b"rcpt"), tag_no_case(b"to:"));
+    let parser = pair(preceded(preamble, forward_path), esmtp_params);
+    map_res(parser, |(forward_path, params)| {
+        rcpt_params(&params).map(|dsn| Cmd::Rcpt {
+            forward_path,
+            dsn,
+            params,
+        })
+    })(buf)
 }
 
-fn data(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn data(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     value(Cmd::Data, tag_no_case(b"data"))(buf)
 }
 
-fn rset(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn bdat(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let last = alt((
+        value(true, pair(space, tag_no_case(b"last"))),
+        value(false, empty),
+    ));
+    let parser = pair(preceded(cmd(b"bdat"), number), last);
+    map(parser, |(size, last)| Cmd::Bdat { size, last })(buf)
+}
+
+fn rset(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     value(Cmd::Rset, tag_no_case(b"rset"))(buf)
 }
 
-fn quit(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn quit(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     value(Cmd::Quit, tag_no_case(b"quit"))(buf)
 }
 
-fn vrfy(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let preamble = preceded(cmd(b"vrfy"), take_all);
-    value(Cmd::Vrfy, preamble)(buf)
+fn vrfy(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parse_arg = preceded(cmd(b"vrfy"), take_all);
+    map(parse_arg, |arg| Cmd::Vrfy { arg })(buf)
 }
 
-fn noop(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn expn(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parse_list = preceded(cmd(b"expn"), take_all);
+    map(parse_list, |list| Cmd::Expn { list })(buf)
+}
+
+fn xclient(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parse_attributes = preceded(cmd(b"xclient"), proxy_attributes);
+    map(parse_attributes, |attributes| Cmd::Xclient { attributes })(buf)
+}
+
+fn xforward(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parse_attributes = preceded(cmd(b"xforward"), proxy_attributes);
+    map(parse_attributes, |attributes| Cmd::Xforward { attributes })(buf)
+}
+
+// Match the name=value attributes of XCLIENT or XFORWARD, the values are xtext encoded
+fn proxy_attributes(buf: &[u8]) -> IResult<&[u8], Vec<(&str, String)>> {
+    let name = map_res(take_while1(|c: u8| c.is_ascii_alphabetic()), str::from_utf8);
+    let value = map_res(take_while1(|c| c > b' ' && c < 0x7f), decode_xtext);
+    separated_list1(space, separated_pair(name, tag(b"="), value))(buf)
+}
+
+fn noop(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     value(Cmd::Noop, tag_no_case(b"noop"))(buf)
 }
 
-fn starttls(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn starttls(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     value(Cmd::StartTls, tag_no_case(b"starttls"))(buf)
 }
 
@@ -119,41 +417,111 @@ fn auth_initial(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     preceded(space, take_while1(is_base64))(buf)
 }
 
+// A base64 response, which may be empty, or "*" to cancel the exchange
 fn auth_response(buf: &[u8]) -> IResult<&[u8], &[u8]> {
-    terminated(take_while1(is_base64), tag("\r\n"))(buf)
+    terminated(alt((tag("*"), take_while(is_base64))), tag("\r\n"))(buf)
 }
 
 fn empty(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     Ok((buf, b"" as &[u8]))
 }
 
-fn auth_plain(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let parser = preceded(tag_no_case(b"plain"), alt((auth_initial, empty)));
+fn auth_plain(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parser = preceded(mechanism(b"plain"), alt((auth_initial, empty)));
     map(parser, sasl_plain_cmd)(buf)
 }
 
-fn auth_login(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let parser = preceded(tag_no_case(b"login"), alt((auth_initial, empty)));
+fn auth_login(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parser = preceded(mechanism(b"login"), alt((auth_initial, empty)));
     map(parser, sasl_login_cmd)(buf)
 }
 
-fn auth(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    preceded(cmd(b"auth"), alt((auth_plain, auth_login)))(buf)
+fn auth_cram_md5(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    value(Cmd::AuthCramMd5, mechanism(b"cram-md5"))(buf)
+}
+
+fn auth_scram_sha256(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parser = preceded(mechanism(b"scram-sha-256"), opt(auth_initial));
+    map(parser, |initial| Cmd::AuthScramSha256 { initial })(buf)
+}
+
+fn auth_oauthbearer(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parser = preceded(mechanism(b"oauthbearer"), opt(auth_initial));
+    map(parser, |initial| Cmd::AuthOAuthBearer { initial })(buf)
+}
+
+fn auth_xoauth2(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parser = preceded(mechanism(b"xoauth2"), opt(auth_initial));
+    map(parser, |initial| Cmd::AuthXOAuth2 { initial })(buf)
+}
+
+fn auth_external(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parser = preceded(mechanism(b"external"), opt(auth_initial));
+    map(parser, |initial| Cmd::AuthExternal { initial })(buf)
+}
+
+// Match the name of a built-in SASL mechanism, which must not be the start of a longer
+// name, e.g. SCRAM-SHA-256-PLUS
+fn mechanism(name: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], &[u8]> + '_ {
+    move |buf: &[u8]| terminated(tag_no_case(name), peek(alt((space, tag(b"\r\n")))))(buf)
+}
+
+// A SASL mechanism name (RFC 4422)
+fn sasl_mechanism(buf: &[u8]) -> IResult<&[u8], &str> {
+    map_res(
+        take_while_m_n(1, 20, |c: u8| {
+            c.is_ascii_alphanumeric() || c == b'-' || c == b'_'
+        }),
+        str::from_utf8,
+    )(buf)
+}
+
+// Any other mechanism, which may be registered on the SessionBuilder
+fn auth_other(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parser = pair(sasl_mechanism, opt(auth_initial));
+    map(parser, |(mechanism, initial)| Cmd::AuthSasl {
+        mechanism,
+        initial,
+    })(buf)
+}
+
+fn auth(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    preceded(
+        cmd(b"auth"),
+        alt((
+            auth_plain,
+            auth_login,
+            auth_cram_md5,
+            auth_scram_sha256,
+            auth_oauthbearer,
+            auth_xoauth2,
+            auth_external,
+            auth_other,
+        )),
+    )(buf)
 }
 
 //---- Helper functions ---------------------------------------------------------
 
+// Result of matching a command and the following space
+type CmdResult<'a> = IResult<&'a [u8], (&'a [u8], &'a [u8])>;
+
 // Return a parser to match the given command
-fn cmd(cmd_tag: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], (&[u8], &[u8])> + '_ {
+fn cmd(cmd_tag: &[u8]) -> impl Fn(&[u8]) -> CmdResult<'_> + '_ {
     move |buf: &[u8]| pair(tag_no_case(cmd_tag), space)(buf)
 }
 
+// Match a decimal number
+fn number(buf: &[u8]) -> IResult<&[u8], usize> {
+    map_res(map_res(digit1, str::from_utf8), str::parse)(buf)
+}
+
 // Match one or more spaces
 fn space(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     take_while1(|b| b == b' ')(buf)
 }
 
-fn sasl_plain_cmd(param: &[u8]) -> Cmd {
+fn sasl_plain_cmd(param: &[u8]) -> Cmd<'_> {
     if param.is_empty() {
         Cmd::AuthPlainEmpty
     } else {
@@ -166,7 +534,7 @@ fn sasl_plain_cmd(param: &[u8]) -> Cmd {
     }
 }
 
-fn sasl_login_cmd(param: &[u8]) -> Cmd {
+fn sasl_login_cmd(param: &[u8]) -> Cmd<'_> {
     if param.is_empty() {
         Cmd::AuthLoginEmpty
     } else {
@@ -205,6 +573,159 @@ pub(crate) fn decode_sasl_login(param: &[u8]) -> String {
     String::from_utf8(decoded).unwrap_or_default()
 }
 
+// Decodes the base64 encoded authorization identity sent with AUTH EXTERNAL
+pub(crate) fn decode_sasl_external(param: &[u8]) -> Option<String> {
+    let decoded = base64::decode(param).ok()?;
+    String::from_utf8(decoded).ok()
+}
+
+// Decodes the base64 encoded CRAM-MD5 response into the username and hex digest
+pub(crate) fn decode_cram_md5(param: &[u8]) -> Option<(String, String)> {
+    let decoded = base64::decode(param).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, digest) = decoded.rsplit_once(' ')?;
+    Some((username.to_string(), digest.to_string()))
+}
+
+// Decodes a SCRAM client-first-message, e.g. "n,,n=user,r=nonce"
+pub(crate) fn decode_scram_client_first(msg: &[u8]) -> Option<ScramClientFirst> {
+    let msg = str::from_utf8(msg).ok()?;
+    let mut gs2 = msg.splitn(3, ',');
+    let cbind_flag = gs2.next()?;
+    let authzid = gs2.next()?;
+    let bare = gs2.next()?;
+    // Channel binding is not supported, so the client must not require it
+    if cbind_flag != "n" && cbind_flag != "y" {
+        return None;
+    }
+    let mut attributes = bare.split(',');
+    let username = scram_attribute(attributes.next()?, 'n')?;
+    let username = decode_saslname(username)?;
+    if !authzid.is_empty() && scram_attribute(authzid, 'a').and_then(decode_saslname)? != username {
+        return None;
+    }
+    let nonce = scram_attribute(attributes.next()?, 'r')?;
+    Some(ScramClientFirst {
+        gs2_header: msg[..msg.len() - bare.len()].to_string(),
+        username,
+        nonce: nonce.to_string(),
+        bare: bare.to_string(),
+    })
+}
+
+// Decodes a SCRAM client-final-message, e.g. "c=biws,r=nonce,p=proof"
+pub(crate) fn decode_scram_client_final(msg: &[u8]) -> Option<ScramClientFinal> {
+    let msg = str::from_utf8(msg).ok()?;
+    let (without_proof, proof) = msg.rsplit_once(',')?;
+    let proof = base64::decode(scram_attribute(proof, 'p')?).ok()?;
+    let mut attributes = without_proof.split(',');
+    let channel_binding = scram_attribute(attributes.next()?, 'c')?;
+    let nonce = scram_attribute(attributes.next()?, 'r')?;
+    Some(ScramClientFinal {
+        channel_binding: channel_binding.to_string(),
+        nonce: nonce.to_string(),
+        proof,
+        without_proof: without_proof.to_string(),
+    })
+}
+
+// Decodes an OAUTHBEARER client response (RFC 7628) into the user and bearer token,
+// e.g. "n,a=user@example.com,\x01auth=Bearer token\x01\x01"
+pub(crate) fn decode_oauthbearer(msg: &[u8]) -> Option<(String, String)> {
+    let msg = str::from_utf8(msg).ok()?;
+    let (gs2_header, kvpairs) = msg.split_once('\x01')?;
+    let mut gs2 = gs2_header.split(',');
+    let cbind_flag = gs2.next()?;
+    let authzid = gs2.next()?;
+    if cbind_flag != "n" && cbind_flag != "y" || gs2.next() != Some("") {
+        return None;
+    }
+    let user = if authzid.is_empty() {
+        String::new()
+    } else {
+        decode_saslname(scram_attribute(authzid, 'a')?)?
+    };
+    Some((user, bearer_token(kvpairs)?))
+}
+
+// Decodes an XOAUTH2 client response into the user and bearer token,
+// e.g. "user=user@example.com\x01auth=Bearer token\x01\x01"
+pub(crate) fn decode_xoauth2(msg: &[u8]) -> Option<(String, String)> {
+    let msg = str::from_utf8(msg).ok()?;
+    let user = msg.split('\x01').find_map(|kv| kv.strip_prefix("user="))?;
+    Some((user.to_string(), bearer_token(msg)?))
+}
+
+// The token in the auth key of \x01 separated key-value pairs
+fn bearer_token(kvpairs: &str) -> Option<String> {
+    let auth = kvpairs
+        .split('\x01')
+        .find_map(|kv| kv.strip_prefix("auth="))?;
+    let (scheme, token) = auth.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() {
+        Some(token.to_string())
+    } else {
+        None
+    }
+}
+
+// The value of a SCRAM attribute with the given name
+fn scram_attribute(attribute: &str, name: char) -> Option<&str> {
+    attribute.strip_prefix(name)?.strip_prefix('=')
+}
+
+// Decodes a SCRAM username, where ',' and '=' are sent as =2C and =3D
+fn decode_saslname(name: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut parts = name.split('=');
+    decoded.push_str(parts.next()?);
+    for part in parts {
+        if let Some(rest) = part.strip_prefix("2C") {
+            decoded.push(',');
+            decoded.push_str(rest);
+        } else if let Some(rest) = part.strip_prefix("3D") {
+            decoded.push('=');
+            decoded.push_str(rest);
+        } else {
+            return None;
+        }
+    }
+    Some(decoded)
+}
+
+// Decodes an xtext value, where characters can be encoded as +XX hex
+pub(crate) fn decode_xtext(encoded: &[u8]) -> Result<String, ()> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut it = encoded.iter();
+    while let Some(c) = it.next() {
+        if *c == b'+' {
+            let hex = [*it.next().ok_or(())?, *it.next().ok_or(())?];
+            let hex = str::from_utf8(&hex).map_err(|_| ())?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| ())?);
+        } else {
+            decoded.push(*c);
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| ())
+}
+
+// Decodes the value of a NOTIFY parameter
+fn decode_notify(value: &[u8]) -> Result<Notify, ()> {
+    if value.eq_ignore_ascii_case(b"never") {
+        return Ok(Notify::default());
+    }
+    let mut notify = Notify::default();
+    for condition in value.split(|c| *c == b',') {
+        match condition.to_ascii_lowercase().as_slice() {
+            b"success" => notify.success = true,
+            b"failure" => notify.failure = true,
+            b"delay" => notify.delay = true,
+            _ => return Err(()),
+        }
+    }
+    Ok(notify)
+}
+
 fn next_string(it: &mut dyn Iterator<Item = &[u8]>) -> String {
     it.next()
         .map(|s| str::from_utf8(s).unwrap_or_default())
@@ -214,10 +735,299 @@ fn next_string(it: &mut dyn Iterator<Item = &[u8]>) -> String {
 
 //---- Tests --------------------------------------------------------------------
 
+#[cfg(test)]
 mod tests {
     #[allow(unused_imports)]
     use super::*;
 
+    #[test]
+    fn unknown_commands() {
+        match parse(b"XDIAG session one\r\n") {
+            Ok(Cmd::Unknown { verb, args }) => {
+                assert_eq!(verb, "XDIAG");
+                assert_eq!(args, "session one");
+            }
+            _ => panic!("Unknown command incorrectly parsed"),
+        }
+        match parse(b"x-ping\r\n") {
+            Ok(Cmd::Unknown { verb, args }) => {
+                assert_eq!(verb, "x-ping");
+                assert_eq!(args, "");
+            }
+            _ => panic!("Unknown command without arguments incorrectly parsed"),
+        }
+        // Implemented commands with bad syntax are not passed on
+        assert_eq!(parse(b"mail from:<fish>\r\n").err(), Some(SYNTAX_ERROR));
+        assert_eq!(parse(b"HELO\r\n").err(), Some(SYNTAX_ERROR));
+        assert_eq!(parse(b"<bad>\r\n").err(), Some(SYNTAX_ERROR));
+    }
+
+    #[test]
+    fn mail_size() {
+        let res = parse(b"mail from:<ship@sea.com> size=1024 body=8bitmime\r\n");
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                body,
+                size,
+                ..
+            }) => {
+                assert_eq!(reverse_path.to_string(), "ship@sea.com");
+                assert_eq!(body, BodyType::EightBitMime);
+                assert_eq!(size, Some(1024));
+            }
+            _ => panic!("Mail with size parameter incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn mail_require_tls() {
+        match parse(b"mail from:<ship@sea.com> REQUIRETLS\r\n") {
+            Ok(Cmd::Mail { require_tls, .. }) => assert!(require_tls),
+            _ => panic!("Mail with REQUIRETLS parameter incorrectly parsed"),
+        }
+        match parse(b"mail from:<ship@sea.com>\r\n") {
+            Ok(Cmd::Mail { require_tls, .. }) => assert!(!require_tls),
+            _ => panic!("Mail without REQUIRETLS parameter incorrectly parsed"),
+        }
+        assert_eq!(
+            parse(b"mail from:<ship@sea.com> requiretls=yes\r\n").err(),
+            Some(BAD_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn mail_smtputf8() {
+        let res = parse("mail from:<θάλασσα@παράδειγμα.δοκιμή> smtputf8\r\n".as_bytes());
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                smtputf8,
+                ..
+            }) => {
+                assert_eq!(reverse_path.to_string(), "θάλασσα@παράδειγμα.δοκιμή");
+                assert!(smtputf8);
+            }
+            _ => panic!("Mail with smtputf8 parameter incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn mail_dsn() {
+        let res = parse(b"mail from:<ship@sea.com> RET=HDRS ENVID=QQ314159+2Bx\r\n");
+        match res {
+            Ok(Cmd::Mail { dsn, .. }) => {
+                assert_eq!(dsn.ret, Some(Ret::Headers));
+                assert_eq!(dsn.envid, Some("QQ314159+x".to_owned()));
+            }
+            _ => panic!("Mail with DSN parameters incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn rcpt_dsn() {
+        let res =
+            parse(b"rcpt to:<fish@sea.com> NOTIFY=SUCCESS,DELAY ORCPT=rfc822;fish+40sea.com\r\n");
+        match res {
+            Ok(Cmd::Rcpt {
+                forward_path, dsn, ..
+            }) => {
+                assert_eq!(forward_path.to_string(), "fish@sea.com");
+                let notify = dsn.notify.unwrap();
+                assert!(notify.success && notify.delay && !notify.failure);
+                let orcpt = dsn.orcpt.unwrap();
+                assert_eq!(orcpt.addr_type, "rfc822");
+                assert_eq!(orcpt.address, "fish@sea.com");
+            }
+            _ => panic!("Rcpt with DSN parameters incorrectly parsed"),
+        };
+        match parse(b"rcpt to:<fish@sea.com> NOTIFY=NEVER\r\n") {
+            Ok(Cmd::Rcpt { dsn, .. }) => assert!(dsn.notify.unwrap().is_never()),
+            _ => panic!("Rcpt with NOTIFY=NEVER incorrectly parsed"),
+        };
+        assert!(parse(b"rcpt to:<fish@sea.com> NOTIFY=NEVER,DELAY\r\n").is_err());
+        assert!(parse(b"rcpt to:<fish@sea.com> ORCPT=rfc822;fish+4\r\n").is_err());
+    }
+
+    #[test]
+    fn esmtp_params() {
+        let res = parse(b"mail from:<ship@sea.com> MT-PRIORITY=-3 x-flag SIZE=10\r\n");
+        match res {
+            Ok(Cmd::Mail { size, params, .. }) => {
+                assert_eq!(size, Some(10));
+                let params: Vec<_> = params.iter().collect();
+                assert_eq!(
+                    params,
+                    vec![
+                        ("MT-PRIORITY", Some("-3")),
+                        ("X-FLAG", None),
+                        ("SIZE", Some("10"))
+                    ]
+                );
+            }
+            _ => panic!("Mail with esmtp parameters incorrectly parsed"),
+        };
+        assert!(parse(b"mail from:<ship@sea.com> =3\r\n").is_err());
+    }
+
+    #[test]
+    fn bad_esmtp_params() {
+        let bad = |line: &[u8]| parse(line).err();
+        assert_eq!(
+            bad(b"mail from:<ship@sea.com> size=ten\r\n"),
+            Some(BAD_PARAMETER)
+        );
+        assert_eq!(
+            bad(b"mail from:<ship@sea.com> body\r\n"),
+            Some(BAD_PARAMETER)
+        );
+        assert_eq!(
+            bad(b"rcpt to:<fish@sea.com> notify=sometimes\r\n"),
+            Some(BAD_PARAMETER)
+        );
+        assert_eq!(
+            bad(b"mail from:<ship@sea.com> size=10 SIZE=20\r\n"),
+            Some(BAD_PARAMETER)
+        );
+        assert_eq!(
+            bad(b"mail from:<ship@sea.com> x-flag x-flag\r\n"),
+            Some(BAD_PARAMETER)
+        );
+        assert_eq!(
+            bad(b"rcpt to:<fish@sea.com> notify=never notify=success\r\n"),
+            Some(BAD_PARAMETER)
+        );
+        assert_eq!(bad(b"mail from:<ship@sea.com> =3\r\n"), Some(SYNTAX_ERROR));
+        assert_eq!(
+            bad(b"rcpt to:fish@sea.com size=ten\r\n"),
+            Some(SYNTAX_ERROR)
+        );
+    }
+
+    fn mail_from(line: &[u8]) -> Option<Path> {
+        match parse(line) {
+            Ok(Cmd::Mail { reverse_path, .. }) => Some(reverse_path),
+            _ => None,
+        }
+    }
+
+    fn rcpt_to(line: &[u8]) -> Option<Path> {
+        match parse(line) {
+            Ok(Cmd::Rcpt { forward_path, .. }) => Some(forward_path),
+            _ => None,
+        }
+    }
+
+    fn mailbox(local_part: &str, domain: Host) -> Option<Path> {
+        Some(Path::Mailbox(Mailbox {
+            local_part: local_part.to_owned(),
+            domain,
+        }))
+    }
+
+    #[test]
+    fn null_path() {
+        assert_eq!(mail_from(b"mail from:<>\r\n"), Some(Path::Null));
+        assert_eq!(mail_from(b"mail from:<> size=10\r\n"), Some(Path::Null));
+        assert_eq!(rcpt_to(b"rcpt to:<>\r\n"), None);
+    }
+
+    #[test]
+    fn postmaster_path() {
+        assert_eq!(rcpt_to(b"rcpt to:<Postmaster>\r\n"), Some(Path::Postmaster));
+        assert_eq!(mail_from(b"mail from:<postmaster>\r\n"), None);
+        assert_eq!(
+            rcpt_to(b"rcpt to:<postmaster@sea.com>\r\n"),
+            mailbox("postmaster", Host::Domain("sea.com".to_owned()))
+        );
+    }
+
+    #[test]
+    fn source_route() {
+        assert_eq!(
+            mail_from(b"mail from:<@relay.one,@relay.two:ship@sea.com>\r\n"),
+            mailbox("ship", Host::Domain("sea.com".to_owned()))
+        );
+        assert_eq!(mail_from(b"mail from:<@relay.one:>\r\n"), None);
+    }
+
+    #[test]
+    fn quoted_local_part() {
+        let path = rcpt_to(b"rcpt to:<\"fish \\\"and\\\" chips\"@sea.com>\r\n");
+        assert_eq!(
+            path,
+            mailbox("fish \"and\" chips", Host::Domain("sea.com".to_owned()))
+        );
+        assert_eq!(
+            path.unwrap().to_string(),
+            "\"fish \\\"and\\\" chips\"@sea.com"
+        );
+        // Quotes that are not needed are dropped
+        let path = rcpt_to(b"rcpt to:<\"fish\"@sea.com>\r\n").unwrap();
+        assert_eq!(path.to_string(), "fish@sea.com");
+        assert_eq!(rcpt_to(b"rcpt to:<\"fish@sea.com>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<\"fi\"sh\"@sea.com>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<\"fish\\\"@sea.com>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<\"fi\tsh\"@sea.com>\r\n"), None);
+    }
+
+    #[test]
+    fn malformed_paths() {
+        assert_eq!(rcpt_to(b"rcpt to:<fish>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<fish@>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<@sea.com>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<fi..sh@sea.com>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<fish.@sea.com>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<fish@sea..com>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<fish@-sea.com>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<fish@sea.com\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:fish@sea.com\r\n"), None);
+    }
+
+    #[test]
+    fn address_literals() {
+        assert_eq!(
+            rcpt_to(b"rcpt to:<fish@[192.0.2.1]>\r\n"),
+            mailbox("fish", Host::Address("192.0.2.1".parse().unwrap()))
+        );
+        let path = rcpt_to(b"rcpt to:<fish@[IPv6:2001:db8::1]>\r\n");
+        assert_eq!(
+            path,
+            mailbox("fish", Host::Address("2001:db8::1".parse().unwrap()))
+        );
+        assert_eq!(path.unwrap().to_string(), "fish@[IPv6:2001:db8::1]");
+        assert_eq!(
+            rcpt_to(b"rcpt to:<fish@[x-sea:coral]>\r\n"),
+            mailbox(
+                "fish",
+                Host::Literal {
+                    tag: "x-sea".to_owned(),
+                    content: "coral".to_owned()
+                }
+            )
+        );
+        assert_eq!(rcpt_to(b"rcpt to:<fish@[192.0.2.256]>\r\n"), None);
+        assert_eq!(rcpt_to(b"rcpt to:<fish@[IPv6:192.0.2.1]>\r\n"), None);
+    }
+
+    #[test]
+    fn bdat_chunks() {
+        match parse(b"bdat 1000\r\n") {
+            Ok(Cmd::Bdat { size, last }) => {
+                assert_eq!(size, 1000);
+                assert!(!last);
+            }
+            _ => panic!("Bdat incorrectly parsed"),
+        };
+        match parse(b"BDAT 0 LAST\r\n") {
+            Ok(Cmd::Bdat { size, last }) => {
+                assert_eq!(size, 0);
+                assert!(last);
+            }
+            _ => panic!("Bdat last incorrectly parsed"),
+        };
+    }
+
     #[test]
     fn auth_initial_plain() {
         let res = parse(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
@@ -263,4 +1073,130 @@ mod tests {
             _ => panic!("Auth login without initial response incorrectly parsed"),
         };
     }
+
+    #[test]
+    fn auth_scram() {
+        let res = parse(b"auth scram-sha-256 biwsbj11c2VyLHI9YWJj\r\n");
+        match res {
+            Ok(Cmd::AuthScramSha256 {
+                initial: Some(initial),
+            }) => assert_eq!(initial, b"biwsbj11c2VyLHI9YWJj"),
+            _ => panic!("Auth scram-sha-256 with initial response incorrectly parsed"),
+        };
+        let res = parse(b"auth scram-sha-256\r\n");
+        match res {
+            Ok(Cmd::AuthScramSha256 { initial: None }) => {}
+            _ => panic!("Auth scram-sha-256 without initial response incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn auth_other_mechanism() {
+        let res = parse(b"auth x-token c2VjcmV0\r\n");
+        match res {
+            Ok(Cmd::AuthSasl {
+                mechanism,
+                initial: Some(initial),
+            }) => {
+                assert_eq!(mechanism, "x-token");
+                assert_eq!(initial, b"c2VjcmV0");
+            }
+            _ => panic!("Auth with other mechanism incorrectly parsed"),
+        };
+        let res = parse(b"auth scram-sha-256-plus\r\n");
+        match res {
+            Ok(Cmd::AuthSasl {
+                mechanism,
+                initial: None,
+            }) => assert_eq!(mechanism, "scram-sha-256-plus"),
+            _ => panic!("Auth with a longer name than a built-in mechanism incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn auth_cancel() {
+        assert_eq!(parse_auth_response(b"*\r\n"), Ok(b"*" as &[u8]));
+        assert_eq!(parse_auth_response(b"\r\n"), Ok(b"" as &[u8]));
+    }
+
+    #[test]
+    fn cram_md5_response() {
+        let res = decode_cram_md5(b"dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw");
+        assert_eq!(
+            res,
+            Some((
+                "tim".to_string(),
+                "b913a602c7eda7a495b4e6e7334d3890".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn scram_client_first() {
+        let res = decode_scram_client_first(b"n,a=us=3Der,n=us=3Der,r=abc").unwrap();
+        assert_eq!(res.gs2_header, "n,a=us=3Der,");
+        assert_eq!(res.username, "us=er");
+        assert_eq!(res.nonce, "abc");
+        assert_eq!(res.bare, "n=us=3Der,r=abc");
+        // Channel binding is not supported
+        assert_eq!(
+            decode_scram_client_first(b"p=tls-unique,,n=user,r=abc"),
+            None
+        );
+        // The authorization identity must match the user
+        assert_eq!(decode_scram_client_first(b"n,a=admin,n=user,r=abc"), None);
+    }
+
+    #[test]
+    fn oauthbearer_response() {
+        let res = decode_oauthbearer(
+            b"n,a=user@example.com,\x01host=server.example.com\x01port=143\x01auth=Bearer vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==\x01\x01",
+        );
+        assert_eq!(
+            res,
+            Some((
+                "user@example.com".to_string(),
+                "vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==".to_string()
+            ))
+        );
+        let res = decode_oauthbearer(b"n,,\x01auth=Bearer token\x01\x01");
+        assert_eq!(res, Some((String::new(), "token".to_string())));
+        assert_eq!(decode_oauthbearer(b"n,,\x01auth=Basic token\x01\x01"), None);
+    }
+
+    #[test]
+    fn xoauth2_response() {
+        let res = decode_xoauth2(
+            b"user=someuser@example.com\x01auth=Bearer ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg\x01\x01",
+        );
+        assert_eq!(
+            res,
+            Some((
+                "someuser@example.com".to_string(),
+                "ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg".to_string()
+            ))
+        );
+        assert_eq!(decode_xoauth2(b"auth=Bearer token\x01\x01"), None);
+    }
+
+    #[test]
+    fn xclient_xforward() {
+        match parse(b"XCLIENT ADDR=IPV6:2001:db8::1 HELO=a+3Db.com\r\n") {
+            Ok(Cmd::Xclient { attributes }) => assert_eq!(
+                attributes,
+                vec![
+                    ("ADDR", "IPV6:2001:db8::1".to_string()),
+                    ("HELO", "a=b.com".to_string())
+                ]
+            ),
+            _ => panic!("XCLIENT incorrectly parsed"),
+        }
+        match parse(b"xforward PORT=25\r\n") {
+            Ok(Cmd::Xforward { attributes }) => {
+                assert_eq!(attributes, vec![("PORT", "25".to_string())])
+            }
+            _ => panic!("XFORWARD incorrectly parsed"),
+        }
+        assert_eq!(parse(b"xclient\r\n").err(), Some(SYNTAX_ERROR));
+    }
 }
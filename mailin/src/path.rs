@@ -0,0 +1,123 @@
+use crate::parser::is_atext;
+use std::fmt;
+use std::net::IpAddr;
+
+/// The reverse path given with MAIL or the forward path given with RCPT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Path {
+    /// The null reverse path `<>`, used by notifications such as bounces.
+    /// Only given with MAIL.
+    Null,
+    /// The `<Postmaster>` forward path, without a domain.
+    /// Only given with RCPT.
+    Postmaster,
+    /// A mailbox, with any source route removed
+    Mailbox(Mailbox),
+}
+
+impl Path {
+    /// The mailbox of the path, if it has one
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        match self {
+            Path::Mailbox(mailbox) => Some(mailbox),
+            _ => None,
+        }
+    }
+
+    /// Does the path only contain ASCII? Other paths need SMTPUTF8.
+    pub fn is_ascii(&self) -> bool {
+        match self {
+            Path::Mailbox(mailbox) => mailbox.is_ascii(),
+            _ => true,
+        }
+    }
+}
+
+/// Shows the path without angle brackets, the null path is shown as an empty string
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Path::Null => Ok(()),
+            Path::Postmaster => write!(f, "Postmaster"),
+            Path::Mailbox(mailbox) => mailbox.fmt(f),
+        }
+    }
+}
+
+/// An email address, `local-part@domain`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mailbox {
+    /// The local part, without quotes or quoted-pair escapes
+    pub local_part: String,
+    /// The domain or address literal
+    pub domain: Host,
+}
+
+impl Mailbox {
+    /// Does the mailbox only contain ASCII?
+    pub fn is_ascii(&self) -> bool {
+        self.local_part.is_ascii() && self.domain.is_ascii()
+    }
+}
+
+/// Shows the mailbox, quoting the local part if needed
+impl fmt::Display for Mailbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_dot_string(&self.local_part) {
+            write!(f, "{}", self.local_part)?;
+        } else {
+            write!(f, "\"")?;
+            for c in self.local_part.chars() {
+                if c == '"' || c == '\\' {
+                    write!(f, "\\")?;
+                }
+                write!(f, "{c}")?;
+            }
+            write!(f, "\"")?;
+        }
+        write!(f, "@{}", self.domain)
+    }
+}
+
+/// The domain of a mailbox
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Host {
+    /// A domain name
+    Domain(String),
+    /// An IPv4 or IPv6 address literal, e.g. `[192.0.2.1]` or `[IPv6:2001:db8::1]`
+    Address(IpAddr),
+    /// A general address literal, `[tag:content]`
+    Literal {
+        /// The standardized tag of the literal
+        tag: String,
+        /// The content of the literal
+        content: String,
+    },
+}
+
+impl Host {
+    fn is_ascii(&self) -> bool {
+        match self {
+            Host::Domain(domain) => domain.is_ascii(),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Domain(domain) => write!(f, "{domain}"),
+            Host::Address(IpAddr::V4(ip)) => write!(f, "[{ip}]"),
+            Host::Address(IpAddr::V6(ip)) => write!(f, "[IPv6:{ip}]"),
+            Host::Literal { tag, content } => write!(f, "[{tag}:{content}]"),
+        }
+    }
+}
+
+// Can the local part be sent without quotes?
+fn is_dot_string(local_part: &str) -> bool {
+    local_part
+        .split('.')
+        .all(|atom| !atom.is_empty() && atom.bytes().all(is_atext))
+}
@@ -0,0 +1,140 @@
+use crate::path::{Host, Path};
+use crate::SessionInfo;
+use std::time::{SystemTime, UNIX_EPOCH};
+use ternop::ternary;
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Build the Received: trace header for a message (RFC 5321 section 4.4), e.g.
+//
+// Received: from client.example.com ([192.0.2.1])
+//         by mx.example.com with ESMTPS (cipher TLS13_AES_128_GCM_SHA256) id 4d2f0c8a1b3e5f79
+//         for <user@example.com>;
+//         Sun, 18 Oct 2026 09:05:00 +0000
+//
+// The recipient is only given for messages with a single recipient.
+pub(crate) fn received_header(
+    info: &SessionInfo,
+    by: &str,
+    protocol: &str,
+    to: &[Path],
+    time: SystemTime,
+) -> String {
+    let ip = Host::Address(info.ip());
+    let mut header = match info.helo_domain() {
+        Some(helo) => format!("Received: from {helo} ({ip})\r\n"),
+        None => format!("Received: from {ip}\r\n"),
+    };
+    header += &format!("\tby {by} with {protocol}");
+    if let Some(cipher) = info.tls_cipher() {
+        header += &format!(" (cipher {cipher})");
+    }
+    header += &format!(" id {}", info.id());
+    if let [to] = to {
+        header += &format!("\r\n\tfor <{to}>");
+    }
+    header += &format!(";\r\n\t{}\r\n", rfc5322_date(time));
+    header
+}
+
+// Format a time as an RFC 5322 date in UTC, e.g. "Sun, 18 Oct 2026 09:05:00 +0000"
+fn rfc5322_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    // 1970-01-01 was a Thursday
+    let weekday = WEEKDAYS[((days + 4) % 7) as usize];
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{weekday}, {day} {} {year} {:02}:{:02}:{:02} +0000",
+        MONTHS[month - 1],
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+// Convert a number of days since 1970-01-01 into a (year, month, day) date
+// in the proleptic Gregorian calendar
+fn civil_from_days(days: u64) -> (u64, usize, u64) {
+    // Count from 0000-03-01, so that leap days are at the end of each year
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = ternary!(mp < 10, mp + 3, mp - 9);
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month as usize, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::Mailbox;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(rfc5322_date(at(0)), "Thu, 1 Jan 1970 00:00:00 +0000");
+        assert_eq!(
+            rfc5322_date(at(1_700_000_000)),
+            "Tue, 14 Nov 2023 22:13:20 +0000"
+        );
+        assert_eq!(
+            rfc5322_date(at(951_782_400)),
+            "Tue, 29 Feb 2000 00:00:00 +0000"
+        );
+        assert_eq!(
+            rfc5322_date(at(4_102_444_799)),
+            "Thu, 31 Dec 2099 23:59:59 +0000"
+        );
+    }
+
+    #[test]
+    fn header() {
+        let mut info = SessionInfo::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        info.set_helo_domain("client.sea.com");
+        let to = Path::Mailbox(Mailbox {
+            local_part: "fish".to_string(),
+            domain: Host::Domain("sea.com".to_string()),
+        });
+        let header = received_header(
+            &info,
+            "mx.sea.com",
+            "ESMTP",
+            std::slice::from_ref(&to),
+            at(0),
+        );
+        assert_eq!(
+            header,
+            format!(
+                "Received: from client.sea.com ([192.0.2.1])\r\n\tby mx.sea.com with ESMTP id {}\r\n\tfor <fish@sea.com>;\r\n\tThu, 1 Jan 1970 00:00:00 +0000\r\n",
+                info.id()
+            )
+        );
+        info.set_tls_cipher("TLS13_AES_128_GCM_SHA256");
+        let header = received_header(&info, "mx.sea.com", "ESMTPS", &[to.clone(), to], at(0));
+        assert_eq!(
+            header,
+            format!(
+                "Received: from client.sea.com ([192.0.2.1])\r\n\tby mx.sea.com with ESMTPS (cipher TLS13_AES_128_GCM_SHA256) id {};\r\n\tThu, 1 Jan 1970 00:00:00 +0000\r\n",
+                info.id()
+            )
+        );
+    }
+}
@@ -1,74 +1,184 @@
 use log::trace;
+use std::fmt;
 use std::io;
 
 // Empty response that sends nothing back to the client
 pub(crate) const EMPTY_RESPONSE: Response = Response::empty();
 // Start TLS handshake
 pub(crate) const START_TLS: Response =
-    Response::fixed_action(220, "Ready to start TLS", Action::UpgradeTls);
+    Response::fixed_action(220, (2, 0, 0), "Ready to start TLS", Action::UpgradeTls);
 /// Response to indicate that the SMTP session finished
-pub const GOODBYE: Response = Response::fixed(221, "Goodbye");
+pub const GOODBYE: Response = Response::fixed(221, (2, 0, 0), "Goodbye");
 /// Authentication succeeded
-pub const AUTH_OK: Response = Response::fixed(235, "Authentication succeeded");
+pub const AUTH_OK: Response = Response::fixed(235, (2, 7, 0), "Authentication succeeded");
 /// OK response
-pub const OK: Response = Response::fixed(250, "OK");
-// Non-commital response to VERIFY command
-pub(crate) const VERIFY_RESPONSE: Response = Response::fixed(252, "Maybe");
+pub const OK: Response = Response::fixed(250, (2, 0, 0), "OK");
+/// Non-commital response to VRFY and EXPN commands
+pub const VERIFY_RESPONSE: Response = Response::fixed(252, (2, 0, 0), "Maybe");
 // Empty response sent as an auth challenge.
-pub(crate) const EMPTY_AUTH_CHALLENGE: Response = Response::fixed(334, "");
+pub(crate) const EMPTY_AUTH_CHALLENGE: Response = Response::plain(334, "");
 // Username response sent as an auth challenge for the login mechanism.
 // The message is a base64-encoded string "Username:"
-pub(crate) const USERNAME_AUTH_CHALLENGE: Response = Response::fixed(334, "VXNlcm5hbWU6");
+pub(crate) const USERNAME_AUTH_CHALLENGE: Response = Response::plain(334, "VXNlcm5hbWU6");
 // Password response sent as an auth challenge for the login mechanism.
 // The message is a base64-encoded string "Password:"
-pub(crate) const PASSWORD_AUTH_CHALLENGE: Response = Response::fixed(334, "UGFzc3dvcmQ6");
+pub(crate) const PASSWORD_AUTH_CHALLENGE: Response = Response::plain(334, "UGFzc3dvcmQ6");
 /// Response sent to the client before accepting data
-pub const START_DATA: Response = Response::fixed(354, "Start mail input; end with <CRLF>.<CRLF>");
+pub const START_DATA: Response = Response::plain(354, "Start mail input; end with <CRLF>.<CRLF>");
 // State machine is not accepting commands
 pub(crate) const INVALID_STATE: Response =
-    Response::fixed(421, "Internal service error, closing connection");
+    Response::fixed(421, (4, 3, 0), "Internal service error, closing connection");
 /// Service not available
-pub const NO_SERVICE: Response = Response::fixed(421, "Service not available, closing connection");
+pub const NO_SERVICE: Response =
+    Response::fixed(421, (4, 3, 2), "Service not available, closing connection");
+// Client sent too many bad commands
+pub(crate) const TOO_MANY_ERRORS: Response =
+    Response::fixed(421, (4, 7, 0), "Too many errors, closing connection");
+// Client sent the maximum number of messages for one session
+pub(crate) const TOO_MANY_MESSAGES: Response =
+    Response::fixed(421, (4, 7, 0), "Too many messages, closing connection");
 /// Internal server error
-pub const INTERNAL_ERROR: Response = Response::fixed(451, "Aborted: local error in processing");
+pub const INTERNAL_ERROR: Response =
+    Response::fixed(451, (4, 3, 0), "Aborted: local error in processing");
 /// Insufficient system storage
-pub const OUT_OF_SPACE: Response = Response::fixed(452, "Insufficient system storage");
+pub const OUT_OF_SPACE: Response = Response::fixed(452, (4, 3, 1), "Insufficient system storage");
+// RCPT given after the maximum number of recipients
+pub(crate) const TOO_MANY_RECIPIENTS: Response =
+    Response::fixed(452, (4, 5, 3), "Too many recipients");
 /// Authentication system is not working
-pub const TEMP_AUTH_FAILURE: Response = Response::fixed(454, "Temporary authentication failure");
+pub const TEMP_AUTH_FAILURE: Response =
+    Response::fixed(454, (4, 7, 0), "Temporary authentication failure");
 // Parser error
-pub(crate) const SYNTAX_ERROR: Response = Response::fixed(500, "Syntax error");
+pub(crate) const SYNTAX_ERROR: Response = Response::fixed(500, (5, 5, 2), "Syntax error");
+// Command line is longer than allowed
+pub(crate) const LINE_TOO_LONG: Response = Response::fixed(500, (5, 5, 2), "Line too long");
+// Command contains a CR or LF that is not part of the CRLF line ending
+pub(crate) const BARE_LINE_ENDING: Response =
+    Response::fixed(500, (5, 5, 2), "Bare CR or LF not allowed");
+/// Command not recognized
+pub const UNKNOWN_COMMAND: Response = Response::fixed(500, (5, 5, 1), "Command not recognized");
+// XCLIENT or XFORWARD given an unknown attribute or a bad value
+pub(crate) const BAD_PROXY_ATTRIBUTE: Response =
+    Response::fixed(501, (5, 5, 4), "Bad attribute name or value");
 // Parser found missing parameter
-pub(crate) const MISSING_PARAMETER: Response = Response::fixed(502, "Missing parameter");
+pub(crate) const MISSING_PARAMETER: Response = Response::fixed(502, (5, 5, 4), "Missing parameter");
+// Client cancelled an authentication exchange
+pub(crate) const AUTH_CANCELLED: Response =
+    Response::fixed(501, (5, 7, 0), "Authentication cancelled");
+// Client sent an authentication response that could not be decoded
+pub(crate) const MALFORMED_AUTH_RESPONSE: Response =
+    Response::fixed(501, (5, 5, 2), "Malformed authentication response");
+// AUTH was given a mechanism that is not supported
+pub(crate) const UNKNOWN_AUTH_MECHANISM: Response =
+    Response::fixed(504, (5, 5, 4), "Unrecognized authentication type");
 // Command is unexpected for the current state
-pub(crate) const BAD_SEQUENCE_COMMANDS: Response = Response::fixed(503, "Bad sequence of commands");
+pub(crate) const BAD_SEQUENCE_COMMANDS: Response =
+    Response::fixed(503, (5, 5, 1), "Bad sequence of commands");
+/// Message exceeds the maximum message size
+pub const MESSAGE_TOO_LARGE: Response = Response::fixed(
+    552,
+    (5, 3, 4),
+    "Message size exceeds fixed maximum message size",
+);
 /// User storage quota exceeded
-pub const NO_STORAGE: Response = Response::fixed(552, "Exceeded storage allocation");
+pub const NO_STORAGE: Response = Response::fixed(552, (5, 2, 2), "Exceeded storage allocation");
 /// Authentication required
-pub const AUTHENTICATION_REQUIRED: Response = Response::fixed(530, "Authentication required");
+pub const AUTHENTICATION_REQUIRED: Response =
+    Response::fixed(530, (5, 7, 0), "Authentication required");
 /// Bad authentication attempt
-pub const INVALID_CREDENTIALS: Response = Response::fixed(535, "Invalid credentials");
+pub const INVALID_CREDENTIALS: Response = Response::fixed(535, (5, 7, 8), "Invalid credentials");
 /// Unknown user
-pub const NO_MAILBOX: Response = Response::fixed(550, "Mailbox unavailable");
+pub const NO_MAILBOX: Response = Response::fixed(550, (5, 1, 1), "Mailbox unavailable");
+// XCLIENT or XFORWARD sent by a peer that is not trusted
+pub(crate) const PROXY_NOT_AUTHORIZED: Response =
+    Response::fixed(550, (5, 7, 0), "Insufficient authorization");
 /// Error with HELO
-pub const BAD_HELLO: Response = Response::fixed(550, "Bad HELO");
+pub const BAD_HELLO: Response = Response::fixed(550, (5, 7, 1), "Bad HELO");
 /// IP address on blocklists
-pub const BLOCKED_IP: Response = Response::fixed(550, "IP address on blocklists");
+pub const BLOCKED_IP: Response = Response::fixed(550, (5, 7, 1), "IP address on blocklists");
 /// Invalid mailbox name
-pub const BAD_MAILBOX: Response = Response::fixed(553, "Mailbox name not allowed");
+pub const BAD_MAILBOX: Response = Response::fixed(553, (5, 1, 3), "Mailbox name not allowed");
+// Non-ascii address given without the SMTPUTF8 parameter
+pub(crate) const NON_ASCII_ADDRESS: Response = Response::fixed(
+    553,
+    (5, 6, 7),
+    "Non-ASCII addresses not permitted without SMTPUTF8",
+);
+// REQUIRETLS given before STARTTLS
+pub(crate) const TLS_REQUIRED: Response =
+    Response::fixed(530, (5, 7, 10), "Must issue a STARTTLS command first");
+// MAIL or RCPT parameter has a bad value or is given more than once
+pub(crate) const BAD_PARAMETER: Response =
+    Response::fixed(501, (5, 5, 4), "Bad parameter value or repeated parameter");
+// MAIL or RCPT parameter is not supported
+pub(crate) const UNKNOWN_PARAMETER: Response = Response::fixed(
+    555,
+    (5, 5, 4),
+    "Parameters not recognized or not implemented",
+);
+// Message contains a line that is longer than allowed
+pub(crate) const MESSAGE_LINE_TOO_LONG: Response =
+    Response::fixed(554, (5, 6, 0), "Message contains a line that is too long");
+// Message contains a CR or LF that is not part of a CRLF line ending
+pub(crate) const MESSAGE_BARE_LINE_ENDING: Response =
+    Response::fixed(554, (5, 6, 0), "Message contains a bare CR or LF");
+// End of data straight after a bare CR or LF, which another server could have seen
+// as the end of data followed by more commands
+pub(crate) const SMUGGLED_DATA_END: Response = Response::fixed_action(
+    554,
+    (5, 6, 0),
+    "Bare CR or LF before end of data, closing connection",
+    Action::Close,
+);
+/// Connection refused, sent instead of the greeting
+pub const NO_SMTP_SERVICE: Response =
+    Response::fixed_action(554, (5, 7, 1), "No SMTP service here", Action::Close);
 /// Error handling incoming message
-pub const TRANSACTION_FAILED: Response = Response::fixed(554, "Transaction failed");
+pub const TRANSACTION_FAILED: Response = Response::fixed(554, (5, 0, 0), "Transaction failed");
 
 /// Response contains a code and message to be sent back to the client
 #[derive(Clone, Debug, PartialEq)]
 pub struct Response {
     /// The three digit response code
     pub code: u16,
+    /// The enhanced status code (RFC 3463), if the response has one
+    pub enhanced_status: Option<EnhancedStatus>,
     /// The text message
     message: Message,
     /// Is the response an error response?
     pub is_error: bool,
     /// The action to take after sending the response to the client
     pub action: Action,
+    // Write the enhanced status code, set when ENHANCEDSTATUSCODES is in effect
+    send_enhanced_status: bool,
+}
+
+/// An enhanced status code, e.g. 5.1.1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnhancedStatus {
+    /// The class of the status: 2 (success), 4 (transient) or 5 (permanent)
+    pub class: u8,
+    /// The subject of the status, e.g. 1 for addressing
+    pub subject: u16,
+    /// The detail of the status within the subject
+    pub detail: u16,
+}
+
+impl EnhancedStatus {
+    /// Create an enhanced status code from its class, subject and detail
+    pub const fn new(class: u8, subject: u16, detail: u16) -> Self {
+        Self {
+            class,
+            subject,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for EnhancedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -76,6 +186,7 @@ pub(crate) enum Message {
     Fixed(&'static str),
     Custom(String),
     Dynamic(String, Vec<String>),
+    Multiple(Vec<Response>),
     Empty,
 }
 
@@ -94,8 +205,8 @@ pub enum Action {
 
 impl Response {
     // A response that uses a fixed static string
-    pub(crate) const fn fixed(code: u16, message: &'static str) -> Self {
-        Self::fixed_action(code, message, Response::action_from_code(code))
+    pub(crate) const fn fixed(code: u16, status: (u8, u16, u16), message: &'static str) -> Self {
+        Self::fixed_action(code, status, message, Response::action_from_code(code))
     }
 
     const fn action_from_code(code: u16) -> Action {
@@ -106,22 +217,69 @@ impl Response {
     }
 
     // A response that uses a fixed static string and a given action
-    pub(crate) const fn fixed_action(code: u16, message: &'static str, action: Action) -> Self {
+    pub(crate) const fn fixed_action(
+        code: u16,
+        status: (u8, u16, u16),
+        message: &'static str,
+        action: Action,
+    ) -> Self {
         Self {
             code,
+            enhanced_status: Some(EnhancedStatus::new(status.0, status.1, status.2)),
             message: Message::Fixed(message),
             is_error: (code < 200 || code >= 400),
             action,
+            send_enhanced_status: false,
+        }
+    }
+
+    // A fixed response without an enhanced status code. Used for auth
+    // challenges and intermediate replies, which never carry one.
+    pub(crate) const fn plain(code: u16, message: &'static str) -> Self {
+        Self {
+            code,
+            enhanced_status: None,
+            message: Message::Fixed(message),
+            is_error: (code < 200 || code >= 400),
+            action: Response::action_from_code(code),
+            send_enhanced_status: false,
         }
     }
 
+    /// Start building an application defined response with the given code, which can
+    /// have several lines.
+    ///
+    /// ```
+    /// # use mailin::{Action, EnhancedStatus, Response};
+    /// let res = Response::builder(550)
+    ///     .enhanced_status(EnhancedStatus::new(5, 7, 1))
+    ///     .line("Message rejected by local policy")
+    ///     .line("See https://postmaster.example.com for details")
+    ///     .build();
+    /// assert_eq!(
+    ///     res.buffer().unwrap(),
+    ///     b"550-Message rejected by local policy\r\n550 See https://postmaster.example.com for details\r\n"
+    /// );
+    /// ```
+    pub fn builder(code: u16) -> ResponseBuilder {
+        ResponseBuilder::new(code)
+    }
+
     /// Create an application defined response.
-    pub const fn custom(code: u16, message: String) -> Self {
+    /// The enhanced status code is only sent to clients that were offered
+    /// ENHANCEDSTATUSCODES.
+    pub const fn custom(
+        code: u16,
+        enhanced_status: Option<EnhancedStatus>,
+        message: String,
+    ) -> Self {
         Self {
             code,
+            enhanced_status,
             message: Message::Custom(message),
             is_error: (code < 200 || code >= 400),
             action: Response::action_from_code(code),
+            send_enhanced_status: false,
         }
     }
 
@@ -129,9 +287,36 @@ impl Response {
     pub(crate) fn dynamic(code: u16, head: String, tail: Vec<String>) -> Self {
         Self {
             code,
+            enhanced_status: None,
             message: Message::Dynamic(head, tail),
             is_error: false,
             action: Action::Reply,
+            send_enhanced_status: false,
+        }
+    }
+
+    // Several responses sent together, e.g. the per-recipient responses to
+    // the end of data in LMTP. The response is an error if all the responses are errors.
+    pub(crate) fn multiple(responses: Vec<Response>) -> Self {
+        let first = responses
+            .iter()
+            .find(|r| !r.is_error)
+            .or_else(|| responses.first());
+        let code = first.map(|r| r.code).unwrap_or_default();
+        let enhanced_status = first.and_then(|r| r.enhanced_status);
+        let is_error = responses.iter().all(|r| r.is_error);
+        let action = if responses.iter().any(|r| r.action == Action::Close) {
+            Action::Close
+        } else {
+            Action::Reply
+        };
+        Self {
+            code,
+            enhanced_status,
+            message: Message::Multiple(responses),
+            is_error,
+            action,
+            send_enhanced_status: false,
         }
     }
 
@@ -139,31 +324,58 @@ impl Response {
     pub(crate) const fn empty() -> Self {
         Self {
             code: 0,
+            enhanced_status: None,
             message: Message::Empty,
             is_error: false,
             action: Action::NoReply,
+            send_enhanced_status: false,
         }
     }
 
+    // Send the enhanced status code, if any, when writing the response
+    pub(crate) fn with_enhanced_status(mut self) -> Self {
+        if self.enhanced_status.is_some() {
+            self.send_enhanced_status = true;
+        }
+        if let Message::Multiple(responses) = self.message {
+            self.message = Message::Multiple(
+                responses
+                    .into_iter()
+                    .map(Response::with_enhanced_status)
+                    .collect(),
+            );
+        }
+        self
+    }
+
     /// Write the response to the given writer
     pub fn write_to(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        let status = match self.enhanced_status {
+            Some(status) if self.send_enhanced_status => format!("{} ", status),
+            _ => String::new(),
+        };
         match &self.message {
             Message::Dynamic(ref head, ref tail) => {
                 if tail.is_empty() {
-                    write!(out, "{} {}\r\n", self.code, head)?;
+                    write!(out, "{} {}{}\r\n", self.code, status, head)?;
                 } else {
-                    write!(out, "{}-{}\r\n", self.code, head)?;
+                    write!(out, "{}-{}{}\r\n", self.code, status, head)?;
                     for i in 0..tail.len() {
                         if tail.len() > 1 && i < tail.len() - 1 {
-                            write!(out, "{}-{}\r\n", self.code, tail[i])?;
+                            write!(out, "{}-{}{}\r\n", self.code, status, tail[i])?;
                         } else {
-                            write!(out, "{} {}\r\n", self.code, tail[i])?;
+                            write!(out, "{} {}{}\r\n", self.code, status, tail[i])?;
                         }
                     }
                 }
             }
-            Message::Fixed(s) => write!(out, "{} {}\r\n", self.code, s)?,
-            Message::Custom(s) => write!(out, "{} {}\r\n", self.code, s)?,
+            Message::Fixed(s) => write!(out, "{} {}{}\r\n", self.code, status, s)?,
+            Message::Custom(s) => write!(out, "{} {}{}\r\n", self.code, status, s)?,
+            Message::Multiple(responses) => {
+                for res in responses {
+                    res.write_to(out)?;
+                }
+            }
             Message::Empty => (),
         };
         Ok(())
@@ -187,3 +399,60 @@ impl Response {
         }
     }
 }
+
+/// Builds an application defined response that can have several lines.
+///
+/// The enhanced status code, if any, is written on every line but, as with
+/// `Response::custom`, only to clients that were offered ENHANCEDSTATUSCODES.
+#[derive(Clone, Debug)]
+pub struct ResponseBuilder {
+    code: u16,
+    enhanced_status: Option<EnhancedStatus>,
+    lines: Vec<String>,
+    action: Action,
+}
+
+impl ResponseBuilder {
+    fn new(code: u16) -> Self {
+        Self {
+            code,
+            enhanced_status: None,
+            lines: Vec::new(),
+            action: Response::action_from_code(code),
+        }
+    }
+
+    /// Set the enhanced status code of the response
+    pub fn enhanced_status(&mut self, status: EnhancedStatus) -> &mut Self {
+        self.enhanced_status = Some(status);
+        self
+    }
+
+    /// Add a line of text to the response. Any CR or LF in the text is replaced with a
+    /// space, so that the text cannot end the response early.
+    pub fn line<S: Into<String>>(&mut self, text: S) -> &mut Self {
+        self.lines.push(text.into().replace(['\r', '\n'], " "));
+        self
+    }
+
+    /// Close the connection after sending the response. By default the connection is
+    /// only closed for 221 and 421 responses.
+    pub fn close(&mut self) -> &mut Self {
+        self.action = Action::Close;
+        self
+    }
+
+    /// Build the response. A response without lines has an empty text.
+    pub fn build(&self) -> Response {
+        let mut lines = self.lines.iter().cloned();
+        let head = lines.next().unwrap_or_default();
+        Response {
+            code: self.code,
+            enhanced_status: self.enhanced_status,
+            message: Message::Dynamic(head, lines.collect()),
+            is_error: (self.code < 200 || self.code >= 400),
+            action: self.action.clone(),
+            send_enhanced_status: false,
+        }
+    }
+}
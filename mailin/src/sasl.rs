@@ -0,0 +1,305 @@
+use crate::Response;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+type HmacMd5 = Hmac<Md5>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// A SASL mechanism implemented outside of mailin, registered with
+/// `SessionBuilder::enable_sasl_mechanism`.
+///
+/// # Example
+/// ```
+/// use mailin::{SaslExchange, SaslMechanism, SaslStep, SessionBuilder};
+/// use mailin::response::INVALID_CREDENTIALS;
+///
+/// // A mechanism where the client sends a single shared token
+/// struct SharedToken;
+/// struct SharedTokenExchange;
+///
+/// impl SaslMechanism for SharedToken {
+///     fn name(&self) -> &str {
+///         "X-SHARED-TOKEN"
+///     }
+///
+///     fn exchange(&self) -> Box<dyn SaslExchange> {
+///         Box::new(SharedTokenExchange)
+///     }
+/// }
+///
+/// impl SaslExchange for SharedTokenExchange {
+///     fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+///         match response {
+///             // Ask for the token if it was not sent with AUTH
+///             None => SaslStep::Challenge(Vec::new()),
+///             Some(b"secret") => SaslStep::Success("robot".to_string()),
+///             Some(_) => SaslStep::Failure(INVALID_CREDENTIALS),
+///         }
+///     }
+/// }
+///
+/// let mut builder = SessionBuilder::new("server_name");
+/// builder.enable_sasl_mechanism(SharedToken);
+/// ```
+pub trait SaslMechanism: Send + Sync {
+    /// The name of the mechanism, as advertised in EHLO and given with AUTH
+    fn name(&self) -> &str;
+
+    /// Does the mechanism need a TLS connection? This is true by default, as
+    /// the mechanism is assumed to send credentials in the clear.
+    fn requires_tls(&self) -> bool {
+        true
+    }
+
+    /// Start a new authentication exchange
+    fn exchange(&self) -> Box<dyn SaslExchange>;
+}
+
+/// A single authentication exchange of a `SaslMechanism`
+pub trait SaslExchange: Send + Sync {
+    /// Called with the decoded initial response given with AUTH, or `None` if the
+    /// client did not send one, and then with the decoded response to each challenge.
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep;
+}
+
+/// The next step of a `SaslExchange`
+pub enum SaslStep {
+    /// Send a challenge to the client, mailin takes care of the base64 encoding
+    Challenge(Vec<u8>),
+    /// The client authenticated with the given identity
+    Success(String),
+    /// Authentication failed, send the given response
+    Failure(Response),
+}
+
+/// The stored credentials of a user authenticating with SCRAM-SHA-256 (RFC 7677).
+///
+/// The password itself is not stored, only the salted keys derived from it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScramCredentials {
+    /// The salt used to derive the keys
+    pub salt: Vec<u8>,
+    /// The number of PBKDF2 iterations used to derive the keys
+    pub iterations: u32,
+    /// H(HMAC(SaltedPassword, "Client Key"))
+    pub stored_key: Vec<u8>,
+    /// HMAC(SaltedPassword, "Server Key")
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    /// Derive the stored credentials from a password
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        Self {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: Sha256::digest(client_key).to_vec(),
+            server_key: hmac_sha256(&salted_password, b"Server Key"),
+        }
+    }
+}
+
+// The client-first-message of a SCRAM exchange
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ScramClientFirst {
+    // The GS2 header, e.g. "n,,"
+    pub gs2_header: String,
+    pub username: String,
+    pub nonce: String,
+    // The message without the GS2 header
+    pub bare: String,
+}
+
+// The client-final-message of a SCRAM exchange
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ScramClientFinal {
+    // The base64 encoded channel binding
+    pub channel_binding: String,
+    pub nonce: String,
+    pub proof: Vec<u8>,
+    // The message without the proof
+    pub without_proof: String,
+}
+
+// The server side of a SCRAM-SHA-256 exchange, after the server-first-message was sent
+pub(crate) struct ScramServer {
+    gs2_header: String,
+    nonce: String,
+    credentials: ScramCredentials,
+    // client-first-message-bare + "," + server-first-message
+    auth_message: String,
+}
+
+impl ScramServer {
+    // Start an exchange and return the server-first-message
+    pub fn new(
+        client_first: ScramClientFirst,
+        credentials: ScramCredentials,
+        server_nonce: &str,
+    ) -> (Self, String) {
+        let nonce = format!("{}{}", client_first.nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            base64::encode(&credentials.salt),
+            credentials.iterations
+        );
+        let auth_message = format!("{},{}", client_first.bare, server_first);
+        let server = Self {
+            gs2_header: client_first.gs2_header,
+            nonce,
+            credentials,
+            auth_message,
+        };
+        (server, server_first)
+    }
+
+    // Check the client proof and return the server-final-message
+    pub fn verify(&self, client_final: &ScramClientFinal) -> Option<String> {
+        if client_final.channel_binding != base64::encode(&self.gs2_header)
+            || client_final.nonce != self.nonce
+            || client_final.proof.len() != self.credentials.stored_key.len()
+        {
+            return None;
+        }
+        let auth_message = format!("{},{}", self.auth_message, client_final.without_proof);
+        let client_signature = hmac_sha256(&self.credentials.stored_key, auth_message.as_bytes());
+        let client_key: Vec<u8> = client_final
+            .proof
+            .iter()
+            .zip(client_signature)
+            .map(|(p, s)| p ^ s)
+            .collect();
+        let stored_key = Sha256::digest(client_key);
+        if !bool::from(stored_key.as_slice().ct_eq(&self.credentials.stored_key)) {
+            return None;
+        }
+        let server_signature = hmac_sha256(&self.credentials.server_key, auth_message.as_bytes());
+        Some(format!("v={}", base64::encode(&server_signature)))
+    }
+}
+
+// Credentials for a SCRAM user that does not exist. The exchange carries on and fails
+// at the client-final-message, so unknown users look the same as a wrong password
+// (RFC 5802 section 5.1). The salt is the same for every attempt with the username.
+pub(crate) fn scram_unknown_user(username: &str) -> ScramCredentials {
+    static SALT_SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+    let secret = SALT_SECRET.get_or_init(|| rand::thread_rng().gen());
+    let salt = hmac_sha256(secret, username.as_bytes());
+    // No proof matches the keys of a random password
+    let password: [u8; 32] = rand::thread_rng().gen();
+    let client_key = hmac_sha256(&password, b"Client Key");
+    ScramCredentials {
+        salt: salt[..16].to_vec(),
+        iterations: 4096,
+        stored_key: Sha256::digest(client_key).to_vec(),
+        server_key: hmac_sha256(&password, b"Server Key"),
+    }
+}
+
+// The error challenge sent when a bearer token is rejected (RFC 7628 section 3.2.2)
+pub(crate) const BEARER_ERROR: &str = r#"{"status":"invalid_token","schemes":"bearer"}"#;
+
+// A random nonce for a SCRAM exchange
+pub(crate) fn scram_nonce() -> String {
+    let bytes: [u8; 18] = rand::thread_rng().gen();
+    base64::encode(&bytes)
+}
+
+// A CRAM-MD5 challenge in the msg-id format of RFC 2195
+pub(crate) fn cram_md5_challenge(name: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let random: u32 = rand::thread_rng().gen();
+    format!("<{random}.{timestamp}@{name}>")
+}
+
+// Check the hex digest sent by a CRAM-MD5 client
+pub(crate) fn cram_md5_verify(secret: &str, challenge: &str, digest: &str) -> bool {
+    let mut mac = HmacMd5::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(challenge.as_bytes());
+    let expected: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    let digest = digest.to_ascii_lowercase();
+    expected.as_bytes().ct_eq(digest.as_bytes()).into()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{decode_scram_client_final, decode_scram_client_first};
+
+    #[test]
+    fn cram_md5_rfc2195() {
+        assert!(cram_md5_verify(
+            "tanstaaftanstaaf",
+            "<1896.697170952@postoffice.reston.mci.net>",
+            "b913a602c7eda7a495b4e6e7334d3890"
+        ));
+        assert!(cram_md5_verify(
+            "tanstaaftanstaaf",
+            "<1896.697170952@postoffice.reston.mci.net>",
+            "B913A602C7EDA7A495B4E6E7334D3890"
+        ));
+        assert!(!cram_md5_verify(
+            "tanstaaf",
+            "<1896.697170952@postoffice.reston.mci.net>",
+            "b913a602c7eda7a495b4e6e7334d3890"
+        ));
+    }
+
+    #[test]
+    fn scram_rfc7677() {
+        let salt = base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let credentials = ScramCredentials::new("pencil", &salt, 4096);
+        let client_first = decode_scram_client_first(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+        let (server, server_first) =
+            ScramServer::new(client_first, credentials, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0");
+        assert_eq!(
+            server_first,
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        );
+        let client_final = decode_scram_client_final(
+            b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+        )
+        .unwrap();
+        assert_eq!(
+            server.verify(&client_final),
+            Some("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=".to_string())
+        );
+    }
+
+    #[test]
+    fn scram_bad_proof() {
+        let salt = base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let credentials = ScramCredentials::new("not-pencil", &salt, 4096);
+        let client_first = decode_scram_client_first(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+        let (server, _) =
+            ScramServer::new(client_first, credentials, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0");
+        let client_final = decode_scram_client_final(
+            b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+        )
+        .unwrap();
+        assert_eq!(server.verify(&client_final), None);
+    }
+}
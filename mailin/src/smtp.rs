@@ -1,10 +1,17 @@
 use std::net::IpAddr;
 use std::str;
+use std::sync::Arc;
 
-use crate::fsm::StateMachine;
+use crate::dsn::{MailDsn, RcptDsn};
+use crate::fsm::{Config, StateMachine};
+use crate::path::Path;
 use crate::response::*;
-use crate::{AuthMechanism, Handler};
+use crate::{
+    AsyncHandler, AuthMechanism, DisconnectReason, EsmtpParams, Event, Handler, LinePolicy,
+    SaslMechanism, SessionInfo,
+};
 use either::{Left, Right};
+use ternop::ternary;
 
 //------ Types -----------------------------------------------------------------
 
@@ -17,19 +24,38 @@ pub enum Cmd<'a> {
     Helo {
         domain: &'a str,
     },
+    Lhlo {
+        domain: &'a str,
+    },
     Mail {
-        reverse_path: &'a str,
-        is8bit: bool,
+        reverse_path: Path,
+        body: BodyType,
+        size: Option<usize>,
+        smtputf8: bool,
+        require_tls: bool,
+        dsn: MailDsn,
+        params: EsmtpParams,
     },
     Rcpt {
-        forward_path: &'a str,
+        forward_path: Path,
+        dsn: RcptDsn,
+        params: EsmtpParams,
     },
     Data,
     Rset,
     Noop,
     StartTls,
     Quit,
-    Vrfy,
+    Vrfy {
+        arg: &'a str,
+    },
+    Expn {
+        list: &'a str,
+    },
+    Bdat {
+        size: usize,
+        last: bool,
+    },
     AuthLogin {
         username: String,
     },
@@ -40,16 +66,95 @@ pub enum Cmd<'a> {
     },
     AuthLoginEmpty,
     AuthPlainEmpty,
+    AuthCramMd5,
+    AuthScramSha256 {
+        initial: Option<&'a [u8]>,
+    },
+    AuthOAuthBearer {
+        initial: Option<&'a [u8]>,
+    },
+    AuthXOAuth2 {
+        initial: Option<&'a [u8]>,
+    },
+    AuthExternal {
+        initial: Option<&'a [u8]>,
+    },
+    // A mechanism that is not built into mailin
+    AuthSasl {
+        mechanism: &'a str,
+        initial: Option<&'a [u8]>,
+    },
+    // Override the client details, sent by a proxy (Postfix XCLIENT)
+    Xclient {
+        attributes: Vec<(&'a str, String)>,
+    },
+    // Details of the original client for logging, sent by a proxy MTA (Postfix XFORWARD)
+    Xforward {
+        attributes: Vec<(&'a str, String)>,
+    },
+    // A command that is not implemented by mailin, passed to the Handler
+    Unknown {
+        verb: &'a str,
+        args: &'a str,
+    },
     // Dummy command containing client authentication
     AuthResponse {
         response: &'a [u8],
     },
     // Dummy command to signify end of data
     DataEnd,
+    // Dummy command to signify the end of a BDAT chunk
+    ChunkEnd,
     // Dummy command sent when STARTTLS was successful
     StartedTls,
 }
 
+// The BODY parameter given with the MAIL command
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BodyType {
+    #[default]
+    SevenBit,
+    EightBitMime,
+    BinaryMime,
+}
+
+impl BodyType {
+    pub fn is8bit(self) -> bool {
+        self != BodyType::SevenBit
+    }
+}
+
+impl Cmd<'_> {
+    // Commands that end a group of pipelined commands, the client waits for
+    // the response before sending anything else (RFC 2920, RFC 4954)
+    fn is_sync_point(&self) -> bool {
+        matches!(
+            self,
+            Cmd::Ehlo { .. }
+                | Cmd::Helo { .. }
+                | Cmd::Lhlo { .. }
+                | Cmd::Data
+                | Cmd::Noop
+                | Cmd::StartTls
+                | Cmd::Quit
+                | Cmd::Vrfy { .. }
+                | Cmd::Expn { .. }
+                | Cmd::Xclient { .. }
+                | Cmd::AuthLogin { .. }
+                | Cmd::AuthPlain { .. }
+                | Cmd::AuthLoginEmpty
+                | Cmd::AuthPlainEmpty
+                | Cmd::AuthCramMd5
+                | Cmd::AuthScramSha256 { .. }
+                | Cmd::AuthOAuthBearer { .. }
+                | Cmd::AuthXOAuth2 { .. }
+                | Cmd::AuthExternal { .. }
+                | Cmd::AuthSasl { .. }
+                | Cmd::AuthResponse { .. }
+        )
+    }
+}
+
 pub(crate) struct Credentials {
     pub authorization_id: String,
     pub authentication_id: String,
@@ -58,11 +163,15 @@ pub(crate) struct Credentials {
 
 /// A single smtp session connected to a single client
 pub struct Session<H: Handler> {
-    name: String,
     handler: H,
     fsm: StateMachine,
 }
 
+/// A single smtp session that awaits the decisions of an `AsyncHandler`
+pub struct AsyncSession<H: AsyncHandler> {
+    session: Session<H>,
+}
+
 #[derive(Clone)]
 /// Builds an smtp `Session`
 ///
@@ -87,6 +196,16 @@ pub struct SessionBuilder {
     start_tls_extension: bool,
     insecure_allow_plaintext_auth: bool,
     auth_mechanisms: Vec<AuthMechanism>,
+    sasl_mechanisms: Vec<Arc<dyn SaslMechanism>>,
+    max_message_size: Option<usize>,
+    esmtp_keywords: Vec<String>,
+    lmtp: bool,
+    line_policy: LinePolicy,
+    received_header: bool,
+    max_recipients: Option<usize>,
+    max_bad_commands: Option<usize>,
+    max_messages: Option<usize>,
+    trusted_peers: Vec<IpAddr>,
 }
 
 impl SessionBuilder {
@@ -97,6 +216,16 @@ impl SessionBuilder {
             start_tls_extension: false,
             insecure_allow_plaintext_auth: false,
             auth_mechanisms: Vec::with_capacity(4),
+            sasl_mechanisms: Vec::new(),
+            max_message_size: None,
+            esmtp_keywords: Vec::new(),
+            lmtp: false,
+            line_policy: LinePolicy::default(),
+            received_header: false,
+            max_recipients: None,
+            max_bad_commands: None,
+            max_messages: None,
+            trusted_peers: Vec::new(),
         }
     }
 
@@ -112,6 +241,14 @@ impl SessionBuilder {
         self
     }
 
+    /// Enable authentication with a SASL mechanism that is not built into mailin.
+    ///
+    /// The mechanism is advertised after the `AuthMechanism`s enabled with `enable_auth`.
+    pub fn enable_sasl_mechanism<M: SaslMechanism + 'static>(&mut self, mechanism: M) -> &mut Self {
+        self.sasl_mechanisms.push(Arc::new(mechanism));
+        self
+    }
+
     /// Allow authentication over plaintext and advertise authentication mechanisms before a connection
     /// was upgraded to TLS with STARTTLS.
     ///
@@ -127,25 +264,143 @@ impl SessionBuilder {
         self
     }
 
+    /// Limit the size of messages accepted by the server (RFC 1870).
+    ///
+    /// The limit is advertised with the SIZE extension. Messages that declare a larger size
+    /// in MAIL FROM, or that turn out to be larger while receiving data, are rejected with a
+    /// 552 response.
+    pub fn max_message_size(&mut self, size: usize) -> &mut Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    /// Speak LMTP (RFC 2033) instead of SMTP.
+    ///
+    /// Clients greet the server with LHLO and, at the end of data, receive a response for
+    /// each recipient from `Handler::data_end_lmtp`.
+    pub fn enable_lmtp(&mut self) -> &mut Self {
+        self.lmtp = true;
+        self
+    }
+
+    /// Accept a MAIL or RCPT parameter that is handled by the `Handler`.
+    ///
+    /// Parameters that are not handled by mailin, or enabled with this method, are
+    /// rejected with a 555 response.
+    pub fn enable_esmtp_param<S: Into<String>>(&mut self, keyword: S) -> &mut Self {
+        self.esmtp_keywords.push(keyword.into());
+        self
+    }
+
+    /// Set how strictly lines sent by clients are checked, the default is
+    /// `LinePolicy::Normalize`.
+    pub fn line_policy(&mut self, policy: LinePolicy) -> &mut Self {
+        self.line_policy = policy;
+        self
+    }
+
+    /// Add a `Received:` trace header (RFC 5321 section 4.4) to incoming messages.
+    ///
+    /// The header is sent to `Handler::data` before the first line of each message.
+    /// It names the client, this server, the protocol, the TLS cipher given with
+    /// `Session::set_tls_cipher`, the session id and, for messages with a single
+    /// recipient, the recipient.
+    pub fn enable_received_header(&mut self) -> &mut Self {
+        self.received_header = true;
+        self
+    }
+
+    /// Limit the number of recipients in a mail transaction.
+    ///
+    /// Further RCPT commands are rejected with a 452 response, which tells the client to
+    /// send to the remaining recipients in another transaction.
+    pub fn max_recipients(&mut self, max: usize) -> &mut Self {
+        self.max_recipients = Some(max);
+        self
+    }
+
+    /// Limit the number of bad commands, such as syntax errors and commands sent out of
+    /// sequence, that a client can send. The session is closed with a 421 response once
+    /// the limit is exceeded.
+    pub fn max_bad_commands(&mut self, max: usize) -> &mut Self {
+        self.max_bad_commands = Some(max);
+        self
+    }
+
+    /// Limit the number of messages accepted in a session. Once the limit is reached,
+    /// a MAIL command is answered with a 421 response and the session is closed.
+    /// Transactions that are reset or rejected do not count.
+    pub fn max_messages(&mut self, max: usize) -> &mut Self {
+        self.max_messages = Some(max);
+        self
+    }
+
+    /// Trust a peer, such as a proxy or a Postfix frontend, to use the XCLIENT and
+    /// XFORWARD commands. Can be called for each trusted peer.
+    ///
+    /// XCLIENT overrides the client address, host name, HELO domain and login seen by
+    /// the `Handler` in `SessionInfo`. XFORWARD gives the original client of the next
+    /// mail transaction, as `SessionInfo::forwarded`.
+    pub fn trust_peer(&mut self, ip: IpAddr) -> &mut Self {
+        self.trusted_peers.push(ip);
+        self
+    }
+
     /// Build a new session to handle a connection from the given ip address
     pub fn build<H: Handler>(&self, remote: IpAddr, handler: H) -> Session<H> {
         Session {
-            name: self.name.clone(),
             handler,
             fsm: StateMachine::new(
                 remote,
-                self.auth_mechanisms.clone(),
-                self.start_tls_extension,
-                self.insecure_allow_plaintext_auth,
+                Config {
+                    name: self.name.clone(),
+                    auth_mechanisms: self.auth_mechanisms.clone(),
+                    sasl_mechanisms: self.sasl_mechanisms.clone(),
+                    allow_start_tls: self.start_tls_extension,
+                    insecure_allow_plaintext_auth: self.insecure_allow_plaintext_auth,
+                    max_size: self.max_message_size,
+                    esmtp_keywords: self.esmtp_keywords.clone(),
+                    lmtp: self.lmtp,
+                    line_policy: self.line_policy,
+                    received_header: self.received_header,
+                    max_recipients: self.max_recipients,
+                    max_bad_commands: self.max_bad_commands,
+                    max_messages: self.max_messages,
+                    trusted_peers: self.trusted_peers.clone(),
+                },
             ),
         }
     }
+
+    /// Build a new session, that makes decisions with an `AsyncHandler`, to handle a
+    /// connection from the given ip address
+    pub fn build_async<H: AsyncHandler>(&self, remote: IpAddr, handler: H) -> AsyncSession<H> {
+        AsyncSession {
+            session: self.build(remote, handler),
+        }
+    }
 }
 
 impl<H: Handler> Session<H> {
-    /// Get a greeting to send to the client
-    pub fn greeting(&self) -> Response {
-        Response::dynamic(220, format!("{} ESMTP", self.name), Vec::new())
+    /// Get a greeting to send to the client.
+    ///
+    /// The `Handler` is asked whether to accept the connection first. If the connection
+    /// is refused, the response from the handler is returned with `Action::Close`.
+    pub fn greeting(&mut self) -> Response {
+        let res = self.fsm.connect(&mut self.handler);
+        res.log();
+        res
+    }
+
+    /// The connection to the client has been closed. The `Handler` is told why and
+    /// any mail transaction in progress is aborted.
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
+        self.fsm.disconnect(&mut self.handler, reason);
+    }
+
+    /// Information about the session, as given to the `Handler`
+    pub fn info(&self) -> &SessionInfo {
+        self.fsm.info()
     }
 
     /// STARTTLS active
@@ -153,6 +408,21 @@ impl<H: Handler> Session<H> {
         self.command(Cmd::StartedTls);
     }
 
+    /// STARTTLS active and the client presented a certificate.
+    ///
+    /// The certificates are DER encoded, starting with the client certificate, and
+    /// must have been verified by the TLS layer. They are used for AUTH EXTERNAL.
+    pub fn tls_active_with_peer_certificates(&mut self, certificates: Vec<Vec<u8>>) {
+        self.fsm.set_peer_certificates(certificates);
+        self.command(Cmd::StartedTls);
+    }
+
+    /// Set the name of the negotiated TLS cipher suite, which is shown in
+    /// `Received:` headers and `SessionInfo`
+    pub fn set_tls_cipher(&mut self, cipher: &str) {
+        self.fsm.set_tls_cipher(cipher);
+    }
+
     /// Process a line sent by the client.
     ///
     /// Returns a response that should be written back to the client.
@@ -179,13 +449,171 @@ impl<H: Handler> Session<H> {
     /// assert_eq!(&msg, b"250 OK\r\n");
     /// ```
     pub fn process(&mut self, line: &[u8]) -> Response {
+        self.process_line(line).0
+    }
+
+    /// Process a buffer that can hold several pipelined lines (RFC 2920).
+    ///
+    /// Complete lines, and the binary data of BDAT chunks, are processed until the buffer is exhausted or a command is found
+    /// that the client must wait on before sending more commands (EHLO, DATA, STARTTLS,
+    /// AUTH, QUIT, etc). Returns the number of bytes consumed and the responses, in
+    /// order, that should be written back to the client. Lines that need no reply do
+    /// not produce a response. An incomplete line at the end of the buffer is not
+    /// consumed.
+    ///
+    /// All the returned responses should be written and flushed before processing the
+    /// rest of the buffer. If the last response upgrades to TLS, any unconsumed bytes
+    /// must be discarded.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin::{Session, SessionBuilder, Handler};
+    ///
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// # struct EmptyHandler{};
+    /// # impl Handler for EmptyHandler{};
+    /// # let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    /// # let handler = EmptyHandler{};
+    /// # let mut session = SessionBuilder::new("name").build(addr, handler);
+    /// # session.process(b"EHLO example.com\r\n");
+    /// let buf = b"MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nSubj";
+    /// let (consumed, responses) = session.process_buffer(buf);
+    ///
+    /// // Processing stops after DATA
+    /// assert_eq!(&buf[consumed..], b"Subj");
+    /// let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+    /// assert_eq!(codes, vec![250, 250, 354]);
+    /// ```
+    pub fn process_buffer(&mut self, buf: &[u8]) -> (usize, Vec<Response>) {
+        let mut consumed = 0;
+        let mut responses = Vec::new();
+        while let Some(line) = next_line(&buf[consumed..], self.chunk_remaining()) {
+            consumed += line.len();
+            let (response, is_sync_point) = self.process_line(line);
+            if push_response(&mut responses, response, is_sync_point) {
+                break;
+            }
+        }
+        (consumed, responses)
+    }
+
+    /// The number of bytes of binary data the session expects for the current BDAT chunk
+    /// (RFC 3030).
+    ///
+    /// While this is non-zero, the data sent to `process` is treated as raw chunk data,
+    /// rather than a line, and must not be longer than the returned size.
+    /// `process_buffer` takes care of this automatically.
+    pub fn chunk_remaining(&self) -> usize {
+        self.fsm.chunk_remaining()
+    }
+
+    /// Process a line sent by the client, returning decisions as events rather than
+    /// asking the `Handler`.
+    ///
+    /// This works as `process`, except that the decisions on HELO, MAIL, RCPT, DATA and
+    /// the end of a message are returned as an `Event`. The session then waits for the
+    /// decision to be given to `resume`, and no more input can be processed until then.
+    /// This lets decisions that need I/O, such as database or DNS lookups, be made
+    /// without blocking. The other `Handler` methods, such as `Handler::data`, are
+    /// still called.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin::{Event, Session, SessionBuilder, Handler};
+    /// use mailin::response::NO_MAILBOX;
+    ///
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// # struct EmptyHandler{};
+    /// # impl Handler for EmptyHandler{};
+    /// # let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    /// # let handler = EmptyHandler{};
+    /// # let mut session = SessionBuilder::new("name").build(addr, handler);
+    /// # session.process(b"HELO example.com\r\n");
+    /// # session.process(b"MAIL FROM:<a@example.com>\r\n");
+    /// let event = session.process_event(b"RCPT TO:<b@example.com>\r\n");
+    /// if let Event::NeedRcptDecision { to, .. } = event {
+    ///     // Look up the recipient, then resume the session with the decision
+    ///     assert_eq!(to.to_string(), "b@example.com");
+    ///     let event = session.resume(NO_MAILBOX);
+    ///     assert_eq!(event, Event::Response(NO_MAILBOX));
+    /// }
+    /// ```
+    pub fn process_event(&mut self, line: &[u8]) -> Event {
+        self.process_event_line(line).0
+    }
+
+    // Process a line as process_event, along with the pipelining synchronisation flag
+    fn process_event_line(&mut self, line: &[u8]) -> (Event, bool) {
+        self.fsm.defer_decisions(true);
+        let (response, is_sync_point) = self.process_line(line);
+        self.fsm.defer_decisions(false);
+        (self.event(response), is_sync_point)
+    }
+
+    /// Resume a session that is waiting for a decision, as returned by `process_event`.
+    ///
+    /// The decision is the response that the matching `Handler` method would return.
+    /// The session can ask for another decision, e.g. when a BDAT LAST command with an
+    /// empty chunk is accepted.
+    pub fn resume(&mut self, decision: Response) -> Event {
+        self.resume_with(vec![decision])
+    }
+
+    /// Resume an LMTP session that is waiting for `Event::NeedDataEndDecision`, with one
+    /// response for each recipient, as `Handler::data_end_lmtp` would return.
+    ///
+    /// For other decisions only the first response is used.
+    pub fn resume_lmtp(&mut self, decisions: Vec<Response>) -> Event {
+        self.resume_with(decisions)
+    }
+
+    fn resume_with(&mut self, decisions: Vec<Response>) -> Event {
+        self.fsm.defer_decisions(true);
+        let response = self.fsm.resume(&mut self.handler, decisions);
+        self.fsm.defer_decisions(false);
+        let (response, _) = self.respond(response, false);
+        self.event(response)
+    }
+
+    fn event(&mut self, response: Response) -> Event {
+        match self.fsm.take_pending() {
+            Some(event) => event,
+            None => Event::Response(response),
+        }
+    }
+
+    // Process a single line and return the response along with a flag that is set
+    // if the line was a pipelining synchronisation point
+    fn process_line(&mut self, line: &[u8]) -> (Response, bool) {
+        if self.fsm.is_parked() {
+            // No input can be processed until the session is resumed
+            return self.respond(INVALID_STATE, false);
+        }
         // TODO: process within fsm
-        let response = match self.fsm.process_line(&mut self.handler, line) {
-            Left(cmd) => self.command(cmd),
-            Right(res) => res,
+        let line = match self.fsm.check_line(line) {
+            Ok(line) => line,
+            Err(res) => return self.respond(res, false),
+        };
+        let (response, is_sync_point) = match self.fsm.process_line(&mut self.handler, &line) {
+            Left(cmd) => {
+                let is_sync_point = cmd.is_sync_point();
+                let res = self.fsm.line_command(&mut self.handler, cmd, &line);
+                (res, is_sync_point)
+            }
+            Right(res) => (res, false),
         };
+        self.respond(response, is_sync_point)
+    }
+
+    fn respond(&mut self, response: Response, is_sync_point: bool) -> (Response, bool) {
+        let response = self.fsm.count_bad_commands(response);
+        let response = ternary!(
+            self.fsm.enhanced_status_codes(),
+            response.with_enhanced_status(),
+            response
+        );
         response.log();
-        response
+        (response, is_sync_point)
     }
 
     fn command(&mut self, cmd: Cmd) -> Response {
@@ -193,20 +621,149 @@ impl<H: Handler> Session<H> {
     }
 }
 
+// The next complete line at the start of a pipelined buffer, or the binary data of the
+// current BDAT chunk
+fn next_line(buf: &[u8], chunk_remaining: usize) -> Option<&[u8]> {
+    if buf.is_empty() {
+        None
+    } else if chunk_remaining > 0 {
+        Some(&buf[..buf.len().min(chunk_remaining)])
+    } else {
+        let end = buf.iter().position(|b| *b == b'\n')?;
+        Some(&buf[..=end])
+    }
+}
+
+// Add a response to those for a pipelined buffer, returns true if processing must stop
+fn push_response(responses: &mut Vec<Response>, response: Response, is_sync_point: bool) -> bool {
+    let stop =
+        is_sync_point || response.action == Action::Close || response.action == Action::UpgradeTls;
+    if response.action != Action::NoReply {
+        responses.push(response);
+    }
+    stop
+}
+
+impl<H: AsyncHandler> AsyncSession<H> {
+    /// Get a greeting to send to the client, see `Session::greeting`
+    pub fn greeting(&mut self) -> Response {
+        self.session.greeting()
+    }
+
+    /// The connection to the client has been closed, see `Session::disconnect`
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
+        self.session.disconnect(reason);
+    }
+
+    /// Information about the session, as given to the `AsyncHandler`
+    pub fn info(&self) -> &SessionInfo {
+        self.session.info()
+    }
+
+    /// STARTTLS active
+    pub fn tls_active(&mut self) {
+        self.session.tls_active();
+    }
+
+    /// STARTTLS active and the client presented a certificate, see
+    /// `Session::tls_active_with_peer_certificates`
+    pub fn tls_active_with_peer_certificates(&mut self, certificates: Vec<Vec<u8>>) {
+        self.session.tls_active_with_peer_certificates(certificates);
+    }
+
+    /// Set the name of the negotiated TLS cipher suite, see `Session::set_tls_cipher`
+    pub fn set_tls_cipher(&mut self, cipher: &str) {
+        self.session.set_tls_cipher(cipher);
+    }
+
+    /// The number of bytes of binary data the session expects for the current BDAT
+    /// chunk, see `Session::chunk_remaining`
+    pub fn chunk_remaining(&self) -> usize {
+        self.session.chunk_remaining()
+    }
+
+    /// Process a line sent by the client, awaiting any decision made by the
+    /// `AsyncHandler`.
+    ///
+    /// Returns a response that should be written back to the client.
+    pub async fn process(&mut self, line: &[u8]) -> Response {
+        let event = self.session.process_event(line);
+        self.decide(event).await
+    }
+
+    /// Process a buffer that can hold several pipelined lines (RFC 2920), awaiting any
+    /// decisions made by the `AsyncHandler`.
+    ///
+    /// Returns the number of bytes consumed and the responses that should be written
+    /// back to the client, see `Session::process_buffer`.
+    pub async fn process_buffer(&mut self, buf: &[u8]) -> (usize, Vec<Response>) {
+        let mut consumed = 0;
+        let mut responses = Vec::new();
+        while let Some(line) = next_line(&buf[consumed..], self.chunk_remaining()) {
+            consumed += line.len();
+            let (event, is_sync_point) = self.session.process_event_line(line);
+            let response = self.decide(event).await;
+            if push_response(&mut responses, response, is_sync_point) {
+                break;
+            }
+        }
+        (consumed, responses)
+    }
+
+    // Await the decisions the session asks for, until it gives a response
+    async fn decide(&mut self, mut event: Event) -> Response {
+        loop {
+            let handler = &mut self.session.handler;
+            let info = self.session.fsm.info();
+            let lmtp = self.session.fsm.is_lmtp();
+            let decision = match event {
+                Event::Response(res) => return res,
+                Event::NeedHeloDecision { domain } => handler.helo_async(info, &domain).await,
+                Event::NeedMailDecision { from, dsn, params } => {
+                    handler.mail_async(info, &from, &dsn, &params).await
+                }
+                Event::NeedRcptDecision { to, dsn, params } => {
+                    handler.rcpt_async(info, &to, &dsn, &params).await
+                }
+                Event::NeedDataStartDecision {
+                    from,
+                    is8bit,
+                    smtputf8,
+                    require_tls,
+                    to,
+                } => {
+                    let data_start =
+                        handler.data_start_async(info, &from, is8bit, smtputf8, require_tls, &to);
+                    data_start.await
+                }
+                Event::NeedDataEndDecision { to } if lmtp => {
+                    let responses = handler.data_end_lmtp_async(info, &to).await;
+                    event = self.session.resume_lmtp(responses);
+                    continue;
+                }
+                Event::NeedDataEndDecision { .. } => handler.data_end_async(info).await,
+            };
+            event = self.session.resume(decision);
+        }
+    }
+}
+
 //----- Tests ------------------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::fsm::SmtpState;
+    use crate::{ForwardedClient, SaslExchange, SaslStep, ScramCredentials};
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
     use std::net::Ipv4Addr;
-    use ternop::ternary;
 
     struct EmptyHandler {}
     impl Handler for EmptyHandler {}
     struct DataHandler(Vec<u8>);
     impl Handler for DataHandler {
-        fn data(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        fn data(&mut self, _info: &SessionInfo, buf: &[u8]) -> std::io::Result<()> {
             self.0.extend(buf);
             Ok(())
         }
@@ -237,6 +794,21 @@ mod tests {
         SessionBuilder::new("some.name").build(addr, DataHandler(vec![]))
     }
 
+    #[test]
+    fn session_info() {
+        let mut session = new_session();
+        assert_eq!(session.info().ip(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(session.info().id().len(), 16);
+        assert_eq!(session.info().helo_domain(), None);
+        session.process(b"helo a.domain\r\n");
+        assert_eq!(session.info().helo_domain(), Some("a.domain"));
+        session.process(b"ehlo b.domain\r\n");
+        assert_eq!(session.info().helo_domain(), Some("b.domain"));
+        assert!(!session.info().is_tls());
+        assert_eq!(session.info().auth_identity(), None);
+        assert_ne!(session.info().id(), new_session().info().id());
+    }
+
     #[test]
     fn helo_ehlo() {
         let mut session = new_session();
@@ -257,6 +829,21 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Mail);
     }
 
+    #[test]
+    fn mail_from_null_path() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"mail from:<ship>\r\n");
+        assert_eq!(res.code, 500);
+        let res = session.process(b"mail from:<>\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<>\r\n");
+        assert_eq!(res.code, 500);
+        let res = session.process(b"rcpt to:<postmaster>\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+    }
+
     #[test]
     fn domain_badchars() {
         let mut session = new_session();
@@ -345,52 +932,1311 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
     }
 
+    fn new_size_session(max_size: usize) -> Session<DataHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.max_message_size(max_size);
+        builder.build(addr, DataHandler(vec![]))
+    }
+
     #[test]
-    fn rset_hello() {
-        let mut session = new_session();
-        session.process(b"helo some.domain\r\n");
-        session.process(b"mail from:<ship@sea.com>\r\n");
-        let res = session.process(b"rset\r\n");
+    fn size_ehlo() {
+        let mut session = new_size_session(1024);
+        let res = session.process(b"ehlo a.domain\r\n");
+        let greeting = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert_eq!(
+            greeting,
+            "250-server offers extensions:\r\n250-8BITMIME\r\n250-PIPELINING\r\n250-CHUNKING\r\n250-BINARYMIME\r\n250-SMTPUTF8\r\n250-DSN\r\n250-ENHANCEDSTATUSCODES\r\n250 SIZE 1024\r\n"
+                .to_string()
+        )
+    }
+
+    #[test]
+    fn size_declared_too_large() {
+        let mut session = new_size_session(1024);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com> size=1025\r\n");
+        assert_eq!(res.code, 552);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        let res = session.process(b"mail from:<ship@sea.com> size=1024\r\n");
         assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+    }
+
+    #[test]
+    fn size_data_too_large() {
+        let mut session = new_size_session(16);
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 354);
+        let res = session.process(b"Hello World\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b"Hello again\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 552);
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(&session.handler.0, b"Hello World\r\n");
     }
 
     #[test]
-    fn rset_idle() {
-        let mut session = new_session();
-        let res = session.process(b"rset\r\n");
+    fn max_recipients() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .max_recipients(2)
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
         assert_eq!(res.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        let res = session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<whale@sea.com>\r\n");
+        assert_eq!(res.code, 452);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 354);
     }
 
     #[test]
-    fn quit() {
-        let mut session = new_session();
+    fn max_bad_commands() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .max_bad_commands(2)
+            .build(addr, EmptyHandler {});
+        let res = session.process(b"bad\r\n");
+        assert_eq!(res.code, 500);
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res.code, 503);
+        let res = session.process(b"helo a.domain\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"bad\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(res.action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    }
+
+    #[test]
+    fn max_messages() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .max_messages(1)
+            .build(addr, EmptyHandler {});
         session.process(b"helo a.domain\r\n");
-        session.process(b"mail from:<ship@sea.com>\r\n");
-        let res = session.process(b"quit\r\n");
-        assert_eq!(res.code, 221);
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        session.process(b"rset\r\n");
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let (_, responses) = session.process_buffer(b"bdat 5 last\r\nHello");
+        assert_eq!(responses.last().unwrap().code, 250);
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 421);
         assert_eq!(res.action, Action::Close);
         assert_state!(session.fsm.current_state(), SmtpState::Invalid);
     }
 
     #[test]
-    fn vrfy() {
-        let mut session = new_session();
+    fn received_header() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_received_header()
+            .build(addr, DataHandler(vec![]));
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello World\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        let message = String::from_utf8(session.handler.0.clone()).unwrap();
+        let expected = format!(
+            "Received: from a.domain ([127.0.0.1])\r\n\tby some.name with ESMTP id {}\r\n\tfor <fish@sea.com>;\r\n\t",
+            session.info().id()
+        );
+        assert!(message.starts_with(&expected), "{message}");
+        assert!(message.ends_with(" +0000\r\nHello World\r\n"), "{message}");
+    }
+
+    #[test]
+    fn received_header_bdat() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_received_header()
+            .build(addr, DataHandler(vec![]));
         session.process(b"helo a.domain\r\n");
-        let res1 = session.process(b"vrfy kraken\r\n");
-        assert_eq!(res1.code, 252);
-        assert_state!(session.fsm.current_state(), SmtpState::Hello);
         session.process(b"mail from:<ship@sea.com>\r\n");
-        let res2 = session.process(b"vrfy boat\r\n");
-        assert_eq!(res2.code, 503);
-        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        let (_, responses) = session.process_buffer(b"bdat 5 last\r\nHello");
+        assert_eq!(responses.last().unwrap().code, 250);
+        let message = String::from_utf8(session.handler.0.clone()).unwrap();
+        let expected = format!(
+            "Received: from a.domain ([127.0.0.1])\r\n\tby some.name with SMTP id {};\r\n\t",
+            session.info().id()
+        );
+        assert!(message.starts_with(&expected), "{message}");
+        assert!(message.ends_with(" +0000\r\nHello"), "{message}");
     }
 
-    struct AuthHandler {}
-    impl Handler for AuthHandler {
-        fn auth_plain(
+    struct ReceivedHandler(Vec<u8>);
+    impl Handler for ReceivedHandler {
+        fn data(&mut self, _info: &SessionInfo, buf: &[u8]) -> std::io::Result<()> {
+            self.0.extend(buf);
+            Ok(())
+        }
+
+        fn auth_plain(
+            &mut self,
+            _info: &SessionInfo,
+            _authorization_id: &str,
+            _authentication_id: &str,
+            _password: &str,
+        ) -> Response {
+            AUTH_OK
+        }
+    }
+
+    // The protocol named in the Received: header of a message sent after the setup
+    fn received_protocol(start_tls: bool, auth: bool) -> String {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.enable_received_header();
+        if start_tls {
+            builder.enable_start_tls();
+        }
+        if auth {
+            builder
+                .enable_auth(AuthMechanism::Plain)
+                .insecure_enable_plaintext_auth();
+        }
+        let mut session = builder.build(addr, ReceivedHandler(vec![]));
+        session.process(b"ehlo a.domain\r\n");
+        if start_tls {
+            session.process(b"starttls\r\n");
+            session.tls_active();
+            session.process(b"ehlo a.domain\r\n");
+        }
+        if auth {
+            let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+            assert_eq!(res.code, 235);
+        }
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let (_, responses) = session.process_buffer(b"bdat 5 last\r\nHello");
+        assert_eq!(responses.last().unwrap().code, 250);
+        let message = String::from_utf8(session.handler.0.clone()).unwrap();
+        let start = message.find(" with ").unwrap() + 6;
+        let end = start + message[start..].find(' ').unwrap();
+        message[start..end].to_string()
+    }
+
+    #[test]
+    fn received_header_protocol() {
+        assert_eq!(received_protocol(false, false), "ESMTP");
+        assert_eq!(received_protocol(true, false), "ESMTPS");
+        assert_eq!(received_protocol(false, true), "ESMTPA");
+        assert_eq!(received_protocol(true, true), "ESMTPSA");
+    }
+
+    fn response_code(event: Event) -> u16 {
+        match event {
+            Event::Response(res) => res.code,
+            _ => panic!("Expected a response, got {event:?}"),
+        }
+    }
+
+    #[test]
+    fn events() {
+        let mut session = new_data_session();
+        let event = session.process_event(b"ehlo a.domain\r\n");
+        assert_eq!(
+            event,
+            Event::NeedHeloDecision {
+                domain: "a.domain".to_string()
+            }
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        // Nothing else is processed while waiting for a decision
+        let res = session.process(b"noop\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(response_code(session.resume(OK)), 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(session.info().helo_domain(), Some("a.domain"));
+        let event = session.process_event(b"mail from:<ship@sea.com> ret=hdrs\r\n");
+        match event {
+            Event::NeedMailDecision { from, dsn, .. } => {
+                assert_eq!(from.to_string(), "ship@sea.com");
+                assert_eq!(dsn.ret, Some(crate::dsn::Ret::Headers));
+            }
+            _ => panic!("Expected a mail decision, got {event:?}"),
+        }
+        assert_eq!(response_code(session.resume(OK)), 250);
+        let event = session.process_event(b"rcpt to:<kraken@sea.com>\r\n");
+        assert!(matches!(event, Event::NeedRcptDecision { .. }));
+        assert_eq!(response_code(session.resume(NO_MAILBOX)), 550);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        session.process_event(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(response_code(session.resume(OK)), 250);
+        let event = session.process_event(b"data\r\n");
+        match event {
+            Event::NeedDataStartDecision { to, .. } => assert_eq!(to.len(), 1),
+            _ => panic!("Expected a data start decision, got {event:?}"),
+        }
+        assert_eq!(response_code(session.resume(OK)), 354);
+        session.process_event(b"Hello\r\n");
+        let event = session.process_event(b".\r\n");
+        assert!(matches!(event, Event::NeedDataEndDecision { .. }));
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        assert_eq!(response_code(session.resume(TRANSACTION_FAILED)), 554);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(&session.handler.0, b"Hello\r\n");
+        // Resuming a session that is not waiting for a decision is an error
+        assert_eq!(response_code(session.resume(OK)), 421);
+    }
+
+    #[test]
+    fn bdat_events() {
+        let mut session = new_data_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let event = session.process_event(b"bdat 5\r\n");
+        assert!(matches!(event, Event::NeedDataStartDecision { .. }));
+        assert_eq!(session.chunk_remaining(), 0);
+        let event = session.resume(OK);
+        assert!(matches!(event, Event::Response(ref res) if res.action == Action::NoReply));
+        assert_eq!(session.chunk_remaining(), 5);
+        assert_eq!(response_code(session.process_event(b"Hello")), 250);
+        // An empty last chunk asks for the end of data decision straight away
+        let event = session.process_event(b"bdat 0 last\r\n");
+        assert!(matches!(event, Event::NeedDataEndDecision { .. }));
+        assert_eq!(response_code(session.resume(OK)), 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process_event(b"bdat 0 last\r\n");
+        let event = session.resume(OK);
+        assert!(matches!(event, Event::NeedDataEndDecision { .. }));
+        assert_eq!(response_code(session.resume(OK)), 250);
+        assert_eq!(&session.handler.0, b"Hello");
+    }
+
+    // Run a future to completion on the current thread
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    // A future that is not ready the first time it is polled
+    struct Lookup(Option<Response>, bool);
+    impl std::future::Future for Lookup {
+        type Output = Response;
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Response> {
+            if self.1 {
+                std::task::Poll::Ready(self.0.take().unwrap())
+            } else {
+                self.1 = true;
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }
+    }
+
+    impl AsyncHandler for DataHandler {
+        fn rcpt_async(
+            &mut self,
+            _info: &SessionInfo,
+            to: &Path,
+            _dsn: &RcptDsn,
+            _params: &EsmtpParams,
+        ) -> impl std::future::Future<Output = Response> + Send {
+            let res = ternary!(to.to_string() == "kraken@sea.com", NO_MAILBOX, OK);
+            Lookup(Some(res), false)
+        }
+
+        fn data_end_async(
+            &mut self,
+            _info: &SessionInfo,
+        ) -> impl std::future::Future<Output = Response> + Send {
+            let res = ternary!(self.0.is_empty(), TRANSACTION_FAILED, OK);
+            Lookup(Some(res), false)
+        }
+    }
+
+    fn assert_send<T: Send>(value: T) -> T {
+        value
+    }
+
+    #[test]
+    fn async_session() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build_async(addr, DataHandler(vec![]));
+        assert_eq!(session.greeting().code, 220);
+        let mut process = |line: &[u8]| block_on(assert_send(session.process(line)));
+        assert_eq!(process(b"ehlo a.domain\r\n").code, 250);
+        assert_eq!(process(b"mail from:<ship@sea.com>\r\n").code, 250);
+        assert_eq!(process(b"rcpt to:<kraken@sea.com>\r\n").code, 550);
+        assert_eq!(process(b"rcpt to:<fish@sea.com>\r\n").code, 250);
+        assert_eq!(process(b"data\r\n").code, 354);
+        assert_eq!(process(b"Hello\r\n").action, Action::NoReply);
+        assert_eq!(process(b".\r\n").code, 250);
+        assert_eq!(process(b"quit\r\n").code, 221);
+        assert_eq!(session.session.handler.0, b"Hello\r\n");
+    }
+
+    #[test]
+    fn async_session_pipelining() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build_async(addr, DataHandler(vec![]));
+        session.greeting();
+        block_on(session.process(b"ehlo a.domain\r\n"));
+        let buf = b"mail from:<ship@sea.com>\r\nrcpt to:<kraken@sea.com>\r\n\
+                    rcpt to:<fish@sea.com>\r\ndata\r\nHello\r\n";
+        let (consumed, responses) = block_on(assert_send(session.process_buffer(buf)));
+        assert_eq!(&buf[consumed..], b"Hello\r\n");
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![250, 550, 250, 354]);
+        let (consumed, responses) = block_on(session.process_buffer(b"Hello\r\n.\r\nqu"));
+        assert_eq!(consumed, 10);
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![250]);
+        assert_eq!(session.session.handler.0, b"Hello\r\n");
+    }
+
+    fn new_line_policy_session(policy: LinePolicy) -> Session<DataHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.line_policy(policy);
+        builder.build(addr, DataHandler(vec![]))
+    }
+
+    fn start_data(session: &mut Session<DataHandler>) {
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 354);
+    }
+
+    #[test]
+    fn bare_lf_command() {
+        let mut session = new_data_session();
+        let res = session.process(b"helo a.domain\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"noop\rnoop\r\n");
+        assert_eq!(res.code, 500);
+        let mut session = new_line_policy_session(LinePolicy::Strict);
+        let res = session.process(b"helo a.domain\n");
+        assert_eq!(res.code, 500);
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+    }
+
+    #[test]
+    fn command_too_long() {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        let mut line = b"mail from:<".to_vec();
+        line.extend(vec![b'a'; 500]);
+        line.extend(b"@sea.com>\r\n");
+        let res = session.process(&line);
+        assert_eq!(res.code, 500);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn bare_lf_data_normalized() {
+        let mut session = new_data_session();
+        start_data(&mut session);
+        let res = session.process(b"Hello\n");
+        assert_eq!(res.action, Action::NoReply);
+        // A bare LF line with a dot does not end the message
+        let res = session.process(b".\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b"World\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        assert_eq!(&session.handler.0, b"Hello\r\n\r\nWorld\r\n");
+    }
+
+    #[test]
+    fn bare_cr_dot_data_normalized() {
+        let mut session = new_data_session();
+        start_data(&mut session);
+        let buf = b"foo\r.\r\nMAIL FROM:<smuggled@sea.com>\r\n.\r\n";
+        let (consumed, responses) = session.process_buffer(buf);
+        assert_eq!(consumed, buf.len());
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![554]);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert!(session.handler.0.is_empty());
+    }
+
+    const SMUGGLED: &[u8] = b"hello\n.\r\nMAIL FROM:<spoof@bank.com>\r\nRCPT TO:<d@e.f>\r\n\
+                              DATA\r\nsmuggled\r\n.\r\n";
+
+    #[test]
+    fn bare_lf_dot_data_normalized() {
+        let mut session = new_data_session();
+        start_data(&mut session);
+        let (consumed, responses) = session.process_buffer(SMUGGLED);
+        assert_eq!(consumed, SMUGGLED.len());
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![554]);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        // The message is rejected before the smuggled commands reach the handler
+        assert_eq!(&session.handler.0, b"hello\r\n");
+    }
+
+    #[test]
+    fn bare_lf_dot_data_strict() {
+        let mut session = new_line_policy_session(LinePolicy::Strict);
+        start_data(&mut session);
+        let (consumed, responses) = session.process_buffer(SMUGGLED);
+        assert_eq!(consumed, b"hello\n.\r\n".len());
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![554]);
+        assert_eq!(responses[0].action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+        assert!(session.handler.0.is_empty());
+    }
+
+    #[test]
+    fn bare_lf_data_strict() {
+        let mut session = new_line_policy_session(LinePolicy::Strict);
+        start_data(&mut session);
+        let res = session.process(b"Hello\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b".\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b"mail from:<smuggled@sea.com>\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 554);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert!(session.handler.0.is_empty());
+    }
+
+    #[test]
+    fn data_line_too_long() {
+        let mut session = new_data_session();
+        start_data(&mut session);
+        let mut line = vec![b'a'; 1000];
+        line.extend(b"\r\n");
+        let res = session.process(&line);
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 554);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn lenient_line_policy() {
+        let mut session = new_line_policy_session(LinePolicy::Lenient);
+        start_data(&mut session);
+        let mut line = vec![b'a'; 1000];
+        line.extend(b"\n");
+        session.process(&line);
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        assert_eq!(session.handler.0, line);
+    }
+
+    #[test]
+    fn pipelined_transaction() {
+        let mut session = new_data_session();
+        session.process(b"ehlo a.domain\r\n");
+        let buf = b"mail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\nrcpt to:<kraken@sea.com>\r\ndata\r\nHello World\r\n";
+        let (consumed, responses) = session.process_buffer(buf);
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![250, 250, 250, 354]);
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        let rest = &buf[consumed..];
+        assert_eq!(rest, b"Hello World\r\n");
+        let (consumed, responses) = session.process_buffer(b"Hello World\r\n.\r\nrset\r\nqu");
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![250, 250]);
+        assert_eq!(consumed, 22);
+        assert_eq!(&session.handler.0, b"Hello World\r\n");
+    }
+
+    #[test]
+    fn pipelined_starttls() {
+        let mut session = new_auth_session(true);
+        session.process(b"ehlo a.domain\r\n");
+        let buf = b"starttls\r\nmail from:<ship@sea.com>\r\n";
+        let (consumed, responses) = session.process_buffer(buf);
+        assert_eq!(consumed, 10);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].action, Action::UpgradeTls);
+    }
+
+    #[test]
+    fn bdat() {
+        let mut session = new_data_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com> body=binarymime\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 503);
+        let res = session.process(b"bdat 8\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        assert_eq!(session.chunk_remaining(), 8);
+        let res = session.process(b".\r\n\x00");
+        assert_eq!(res.action, Action::NoReply);
+        assert_eq!(session.chunk_remaining(), 4);
+        let res = session.process(b"\r\n.\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Bdat);
+        let res = session.process(b"bdat 0 last\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(&session.handler.0, b".\r\n\x00\r\n.\n");
+    }
+
+    #[test]
+    fn pipelined_bdat() {
+        let mut session = new_data_session();
+        session.process(b"ehlo a.domain\r\n");
+        let buf = b"mail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\nbdat 5\r\nHelloBDAT 6 LAST\r\n Worldquit\r\n";
+        let (consumed, responses) = session.process_buffer(buf);
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![250, 250, 250, 250, 221]);
+        assert_eq!(consumed, buf.len());
+        assert_eq!(&session.handler.0, b"Hello World");
+    }
+
+    #[test]
+    fn bdat_bad_sequence() {
+        let mut session = new_data_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let buf = b"bdat 6\r\nquit\r\nnoop\r\n";
+        let (consumed, responses) = session.process_buffer(buf);
+        assert_eq!(consumed, 20);
+        assert_eq!(responses[0].code, 503);
+        assert_eq!(responses[1].code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+    }
+
+    #[test]
+    fn smtputf8() {
+        let mut session = new_session();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process("mail from:<船@海.com>\r\n".as_bytes());
+        assert_eq!(res.code, 553);
+        let res = session.process("mail from:<船@海.com> smtputf8\r\n".as_bytes());
+        assert_eq!(res.code, 250);
+        let res = session.process("rcpt to:<魚@海.com>\r\n".as_bytes());
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+    }
+
+    #[derive(Default)]
+    struct RequireTlsHandler(Option<bool>);
+    impl Handler for RequireTlsHandler {
+        fn data_start(
+            &mut self,
+            _info: &SessionInfo,
+            _from: &Path,
+            _is8bit: bool,
+            _smtputf8: bool,
+            require_tls: bool,
+            _to: &[Path],
+        ) -> Response {
+            self.0 = Some(require_tls);
+            OK
+        }
+    }
+
+    #[test]
+    fn require_tls() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_start_tls()
+            .build(addr, RequireTlsHandler::default());
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(!ehlo.contains("REQUIRETLS"), "{ehlo}");
+        let res = session.process(b"mail from:<ship@sea.com> requiretls\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"530 5.7.10 Must issue a STARTTLS command first\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 REQUIRETLS\r\n"), "{ehlo}");
+        let res = session.process(b"mail from:<ship@sea.com> requiretls\r\n");
+        assert_eq!(res.code, 250);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 354);
+        assert_eq!(session.handler.0, Some(true));
+    }
+
+    #[test]
+    fn require_tls_unavailable() {
+        let mut session = new_session();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(!ehlo.contains("REQUIRETLS"), "{ehlo}");
+        let res = session.process(b"mail from:<ship@sea.com> requiretls\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"555 5.5.4 Parameters not recognized or not implemented\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn non_ascii_rcpt() {
+        let mut session = new_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process("rcpt to:<魚@海.com>\r\n".as_bytes());
+        assert_eq!(res.code, 553);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+    }
+
+    #[derive(Default)]
+    struct DsnHandler {
+        mail: MailDsn,
+        rcpt: Vec<RcptDsn>,
+    }
+    impl Handler for DsnHandler {
+        fn mail(
+            &mut self,
+            _info: &SessionInfo,
+            _from: &Path,
+            dsn: &MailDsn,
+            _params: &EsmtpParams,
+        ) -> Response {
+            self.mail = dsn.clone();
+            OK
+        }
+
+        fn rcpt(
+            &mut self,
+            _info: &SessionInfo,
+            _to: &Path,
+            dsn: &RcptDsn,
+            _params: &EsmtpParams,
+        ) -> Response {
+            self.rcpt.push(dsn.clone());
+            OK
+        }
+    }
+
+    #[test]
+    fn dsn() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, DsnHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com> ret=full envid=abc\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<fish@sea.com> notify=failure\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        assert_eq!(session.handler.mail.ret, Some(crate::dsn::Ret::Full));
+        assert_eq!(session.handler.mail.envid.as_deref(), Some("abc"));
+        assert!(session.handler.rcpt[0].notify.unwrap().failure);
+        assert_eq!(session.handler.rcpt[1], RcptDsn::default());
+    }
+
+    #[test]
+    fn unknown_params() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.enable_esmtp_param("MT-PRIORITY");
+        let mut session = builder.build(addr, EmptyHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com> x-unknown=1\r\n");
+        assert_eq!(res.code, 555);
+        let res = session.process(b"mail from:<ship@sea.com> mt-priority=3 auth=<>\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<fish@sea.com> x-unknown\r\n");
+        assert_eq!(res.code, 555);
+        let res = session.process(b"rcpt to:<fish@sea.com> mt-priority=3\r\n");
+        assert_eq!(res.code, 250);
+    }
+
+    #[test]
+    fn enhanced_status_codes() {
+        let mut session = new_session();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 2.0.0 OK\r\n");
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"503 5.5.1 Bad sequence of commands\r\n"
+        );
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"354 Start mail input; end with <CRLF>.<CRLF>\r\n"
+        );
+    }
+
+    #[test]
+    fn enhanced_status_codes_after_helo() {
+        let mut session = new_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 OK\r\n");
+    }
+
+    struct LmtpHandler {}
+    impl Handler for LmtpHandler {
+        fn data_end_lmtp(&mut self, _info: &SessionInfo, to: &[Path]) -> Vec<Response> {
+            to.iter()
+                .map(|r| ternary!(r.to_string() == "fish@sea.com", OK, NO_MAILBOX))
+                .collect()
+        }
+    }
+
+    fn new_lmtp_session() -> Session<LmtpHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.enable_lmtp();
+        builder.build(addr, LmtpHandler {})
+    }
+
+    #[test]
+    fn lmtp() {
+        let mut session = new_lmtp_session();
+        let greeting = session.greeting().buffer().unwrap();
+        assert_eq!(greeting, b"220 some.name LMTP\r\n");
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(res.code, 500);
+        let res = session.process(b"lhlo a.domain\r\n");
+        assert_eq!(res.code, 250);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello World\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        assert!(!res.is_error);
+        let reply = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert_eq!(reply, "250 2.0.0 OK\r\n550 5.1.1 Mailbox unavailable\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn lmtp_bdat() {
+        let mut session = new_lmtp_session();
+        session.process(b"lhlo a.domain\r\n");
+        let buf = b"mail from:<ship@sea.com>\r\nrcpt to:<kraken@sea.com>\r\nrcpt to:<fish@sea.com>\r\nbdat 5 last\r\nHello";
+        let (consumed, responses) = session.process_buffer(buf);
+        assert_eq!(consumed, buf.len());
+        let reply = String::from_utf8(responses[3].buffer().unwrap()).unwrap();
+        assert_eq!(reply, "550 5.1.1 Mailbox unavailable\r\n250 2.0.0 OK\r\n");
+    }
+
+    // Gives a fixed list of responses, whatever the number of recipients
+    struct FixedLmtpHandler(Vec<Response>);
+    impl Handler for FixedLmtpHandler {
+        fn data_end_lmtp(&mut self, _info: &SessionInfo, _to: &[Path]) -> Vec<Response> {
+            self.0.clone()
+        }
+    }
+
+    // The LMTP reply to a message for three recipients
+    fn lmtp_reply(responses: Vec<Response>) -> String {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.enable_lmtp();
+        let mut session = builder.build(addr, FixedLmtpHandler(responses));
+        session.process(b"lhlo a.domain\r\n");
+        let buf = b"mail from:<ship@sea.com>\r\nrcpt to:<a@sea.com>\r\nrcpt to:<b@sea.com>\r\n\
+                    rcpt to:<c@sea.com>\r\nbdat 5 last\r\nHello";
+        let (_, responses) = session.process_buffer(buf);
+        String::from_utf8(responses[4].buffer().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn lmtp_wrong_number_of_responses() {
+        assert_eq!(
+            lmtp_reply(vec![NO_MAILBOX, OK]),
+            "550 5.1.1 Mailbox unavailable\r\n250 2.0.0 OK\r\n250 2.0.0 OK\r\n"
+        );
+        assert_eq!(
+            lmtp_reply(vec![OK, OK, OK, NO_MAILBOX]),
+            "250 2.0.0 OK\r\n250 2.0.0 OK\r\n250 2.0.0 OK\r\n"
+        );
+        let failed = "451 4.3.0 Aborted: local error in processing\r\n";
+        assert_eq!(lmtp_reply(vec![]), failed.repeat(3));
+    }
+
+    #[test]
+    fn lmtp_events() {
+        let mut session = new_lmtp_session();
+        session.process(b"lhlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello\r\n");
+        let event = session.process_event(b".\r\n");
+        assert!(matches!(event, Event::NeedDataEndDecision { ref to } if to.len() == 2));
+        // A single response is given for every recipient
+        let event = session.resume(NO_MAILBOX);
+        let Event::Response(res) = event else {
+            panic!("expected a response")
+        };
+        let reply = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert_eq!(reply, "550 5.1.1 Mailbox unavailable\r\n".repeat(2));
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process_event(b".\r\n");
+        let event = session.resume_lmtp(vec![NO_MAILBOX, OK]);
+        let Event::Response(res) = event else {
+            panic!("expected a response")
+        };
+        let reply = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert_eq!(reply, "550 5.1.1 Mailbox unavailable\r\n250 2.0.0 OK\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    impl AsyncHandler for LmtpHandler {}
+
+    #[test]
+    fn lmtp_async_session() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.enable_lmtp();
+        let mut session = builder.build_async(addr, LmtpHandler {});
+        session.greeting();
+        block_on(session.process(b"lhlo a.domain\r\n"));
+        let buf = b"mail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\n\
+                    rcpt to:<kraken@sea.com>\r\ndata\r\n";
+        block_on(session.process_buffer(buf));
+        block_on(session.process(b"Hello\r\n"));
+        let res = block_on(assert_send(session.process(b".\r\n")));
+        let reply = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert_eq!(reply, "250 2.0.0 OK\r\n550 5.1.1 Mailbox unavailable\r\n");
+    }
+
+    #[test]
+    fn lhlo_smtp() {
+        let mut session = new_session();
+        let res = session.process(b"lhlo a.domain\r\n");
+        assert_eq!(res.code, 500);
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+    }
+
+    #[test]
+    fn rset_hello() {
+        let mut session = new_session();
+        session.process(b"helo some.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"rset\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn rset_idle() {
+        let mut session = new_session();
+        let res = session.process(b"rset\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+    }
+
+    #[test]
+    fn quit() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"quit\r\n");
+        assert_eq!(res.code, 221);
+        assert_eq!(res.action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    }
+
+    struct PolicyHandler {}
+    impl Handler for PolicyHandler {
+        fn rcpt(
+            &mut self,
+            _info: &SessionInfo,
+            _to: &Path,
+            _dsn: &RcptDsn,
+            _params: &EsmtpParams,
+        ) -> Response {
+            Response::builder(550)
+                .enhanced_status(EnhancedStatus::new(5, 7, 1))
+                .line("Rejected by policy")
+                .line("See https://postmaster.sea.com")
+                .close()
+                .build()
+        }
+    }
+
+    #[test]
+    fn multiline_response() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, PolicyHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res.code, 550);
+        assert!(res.is_error);
+        assert_eq!(res.action, Action::Close);
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"550-5.7.1 Rejected by policy\r\n550 5.7.1 See https://postmaster.sea.com\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    }
+
+    #[test]
+    fn response_builder_line_endings() {
+        let res = Response::builder(250)
+            .line("First\r\n250 Injected")
+            .line("Last\n")
+            .build();
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"250-First  250 Injected\r\n250 Last \r\n"
+        );
+    }
+
+    struct ExtensionHandler {}
+    impl Handler for ExtensionHandler {
+        fn ehlo_keywords(&mut self, _info: &SessionInfo) -> Vec<String> {
+            vec!["XDIAG".to_string()]
+        }
+
+        fn unknown_command(&mut self, info: &SessionInfo, verb: &str, args: &str) -> Response {
+            if verb.eq_ignore_ascii_case("xdiag") {
+                Response::custom(250, None, format!("{} {}", info.id(), args))
+            } else {
+                UNKNOWN_COMMAND
+            }
+        }
+    }
+
+    #[test]
+    fn custom_extension() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, ExtensionHandler {});
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250-ENHANCEDSTATUSCODES\r\n250 XDIAG\r\n"));
+        let res = session.process(b"xdiag hello\r\n");
+        assert_eq!(res.code, 250);
+        let expected = format!("250 {} hello\r\n", session.info().id());
+        assert_eq!(res.buffer().unwrap(), expected.as_bytes());
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        let res = session.process(b"xother\r\n");
+        assert_eq!(res.code, 500);
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut session = new_session();
+        let res = session.process(b"help\r\n");
+        assert_eq!(res.code, 500);
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+    }
+
+    #[derive(Default)]
+    struct LifecycleHandler {
+        refuse: bool,
+        events: Vec<String>,
+    }
+    impl Handler for LifecycleHandler {
+        fn connect(&mut self, info: &SessionInfo) -> Response {
+            self.events.push(format!("connect {}", info.ip()));
+            ternary!(self.refuse, NO_SMTP_SERVICE, OK)
+        }
+
+        fn rset(&mut self, _info: &SessionInfo) {
+            self.events.push("rset".to_string());
+        }
+
+        fn quit(&mut self, _info: &SessionInfo) {
+            self.events.push("quit".to_string());
+        }
+
+        fn disconnect(&mut self, _info: &SessionInfo, reason: DisconnectReason) {
+            self.events.push(format!("disconnect {reason:?}"));
+        }
+    }
+
+    fn new_lifecycle_session(refuse: bool) -> Session<LifecycleHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let handler = LifecycleHandler {
+            refuse,
+            ..Default::default()
+        };
+        SessionBuilder::new("some.name").build(addr, handler)
+    }
+
+    #[test]
+    fn connect_refused() {
+        let mut session = new_lifecycle_session(true);
+        let res = session.greeting();
+        assert_eq!(res.code, 554);
+        assert_eq!(res.action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+        let res = session.process(b"helo a.domain\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(session.handler.events, vec!["connect 127.0.0.1"]);
+    }
+
+    #[test]
+    fn lifecycle_callbacks() {
+        let mut session = new_lifecycle_session(false);
+        let res = session.greeting();
+        assert_eq!(res.code, 220);
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rset\r\n");
+        session.process(b"quit\r\n");
+        session.disconnect(DisconnectReason::Quit);
+        assert_eq!(
+            session.handler.events,
+            vec!["connect 127.0.0.1", "rset", "quit", "disconnect Quit"]
+        );
+    }
+
+    #[test]
+    fn disconnect_during_data() {
+        let mut session = new_lifecycle_session(false);
+        session.greeting();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello World\r\n");
+        session.disconnect(DisconnectReason::Eof);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+        assert_eq!(
+            session.handler.events,
+            vec!["connect 127.0.0.1", "disconnect Eof"]
+        );
+    }
+
+    #[test]
+    fn server_aborted_transaction() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.max_message_size(8).line_policy(LinePolicy::Strict);
+        let mut session = builder.build(addr, LifecycleHandler::default());
+        session.greeting();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello\nWorld\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 554);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"bdat 12\r\n");
+        let res = session.process(b"Hello World!");
+        assert_eq!(res.code, 552);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(
+            session.handler.events,
+            vec!["connect 127.0.0.1", "rset", "rset"]
+        );
+    }
+
+    #[test]
+    fn vrfy() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        let res1 = session.process(b"vrfy kraken\r\n");
+        assert_eq!(res1.code, 252);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res2 = session.process(b"vrfy boat\r\n");
+        assert_eq!(res2.code, 503);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+    }
+
+    struct DirectoryHandler {}
+    impl Handler for DirectoryHandler {
+        fn vrfy(&mut self, _info: &SessionInfo, arg: &str) -> Response {
+            match arg {
+                "kraken" => Response::custom(250, None, "<kraken@sea.com>".to_string()),
+                _ => NO_MAILBOX,
+            }
+        }
+
+        fn expn(&mut self, _info: &SessionInfo, list: &str) -> Response {
+            match list {
+                "crew" => Response::builder(250)
+                    .line("<captain@sea.com>")
+                    .line("<cook@sea.com>")
+                    .build(),
+                _ => NO_MAILBOX,
+            }
+        }
+    }
+
+    #[test]
+    fn vrfy_expn_handler() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, DirectoryHandler {});
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"vrfy kraken\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 <kraken@sea.com>\r\n");
+        let res = session.process(b"vrfy boat\r\n");
+        assert_eq!(res.code, 550);
+        let res = session.process(b"expn crew\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"250-<captain@sea.com>\r\n250 <cook@sea.com>\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"expn crew\r\n");
+        assert_eq!(res.code, 252);
+    }
+
+    #[derive(Default)]
+    struct ProxyHandler {
+        helo: Option<(IpAddr, Option<String>)>,
+        mail: Option<(Option<String>, Option<ForwardedClient>)>,
+        rsets: usize,
+    }
+    impl Handler for ProxyHandler {
+        fn helo(&mut self, info: &SessionInfo, _domain: &str) -> Response {
+            self.helo = Some((info.ip(), info.client_name().map(str::to_string)));
+            OK
+        }
+
+        fn mail(
+            &mut self,
+            info: &SessionInfo,
+            _from: &Path,
+            _dsn: &MailDsn,
+            _params: &EsmtpParams,
+        ) -> Response {
+            let identity = info.auth_identity().map(str::to_string);
+            self.mail = Some((identity, info.forwarded().cloned()));
+            OK
+        }
+
+        fn rset(&mut self, _info: &SessionInfo) {
+            self.rsets += 1;
+        }
+    }
+
+    fn new_proxy_session() -> Session<ProxyHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        SessionBuilder::new("some.name")
+            .trust_peer(addr)
+            .build(addr, ProxyHandler::default())
+    }
+
+    #[test]
+    fn xclient_untrusted() {
+        let mut session = new_session();
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert!(!String::from_utf8_lossy(&res.buffer().unwrap()).contains("XCLIENT"));
+        let res = session.process(b"xclient ADDR=192.0.2.1\r\n");
+        assert_eq!(res.code, 550);
+        let res = session.process(b"xforward ADDR=192.0.2.1\r\n");
+        assert_eq!(res.code, 550);
+        assert_eq!(session.fsm.info().ip(), Ipv4Addr::new(127, 0, 0, 1));
+    }
+
+    #[test]
+    fn xclient() {
+        let mut session = new_proxy_session();
+        let res = session.process(b"ehlo proxy.domain\r\n");
+        let ehlo = String::from_utf8_lossy(&res.buffer().unwrap()).to_string();
+        assert!(ehlo.contains("250-XCLIENT NAME ADDR HELO LOGIN\r\n"));
+        session.process(b"xforward NAME=forwarded.sea.com\r\n");
+        let res = session.process(b"xclient ADDR=IPV6:2001:db8::1 NAME=client.sea.com\r\n");
+        assert_eq!(res.buffer().unwrap(), b"220 some.name ESMTP\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        assert_eq!(session.handler.rsets, 1);
+        assert!(session.fsm.info().forwarded().is_none());
+        let res = session.process(b"xclient HELO=[UNAVAILABLE] LOGIN=fish\r\n");
+        assert_eq!(res.code, 220);
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            session.handler.helo,
+            Some((ip, Some("client.sea.com".to_string())))
+        );
+        assert_eq!(session.handler.mail, Some((Some("fish".to_string()), None)));
+        assert_eq!(session.fsm.info().helo_domain(), Some("a.domain"));
+        // Not allowed during a mail transaction
+        let res = session.process(b"xclient ADDR=192.0.2.1\r\n");
+        assert_eq!(res.code, 503);
+        session.process(b"rset\r\n");
+        let res = session.process(b"xclient ADDR=ship\r\n");
+        assert_eq!(res.code, 501);
+        let res = session.process(b"xclient PORT=25\r\n");
+        assert_eq!(res.code, 501);
+        let res = session.process(b"xclient HELO=x+0D+0AEvil:+20y\r\n");
+        assert_eq!(res.code, 501);
+        assert_eq!(session.fsm.info().helo_domain(), Some("a.domain"));
+        assert_eq!(session.fsm.info().ip(), ip);
+    }
+
+    #[test]
+    fn xforward() {
+        let mut session = new_proxy_session();
+        session.process(b"ehlo proxy.domain\r\n");
+        let res = session.process(b"xforward NAME=client.sea.com ADDR=192.0.2.1 PORT=2525\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"xforward HELO=boat+2Esea.com IDENT=[UNAVAILABLE]\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"xforward PORT=fish\r\n");
+        assert_eq!(res.code, 501);
+        let res = session.process(b"xforward IDENT=x+7Fy\r\n");
+        assert_eq!(res.code, 501);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let forwarded = ForwardedClient {
+            name: Some("client.sea.com".to_string()),
+            addr: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            port: Some(2525),
+            helo: Some("boat.sea.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(session.handler.mail, Some((None, Some(forwarded))));
+        // The forwarded client only applies to one transaction
+        session.process(b"rset\r\n");
+        assert!(session.fsm.info().forwarded().is_none());
+    }
+
+    struct AuthHandler {}
+    impl Handler for AuthHandler {
+        fn auth_plain(
             &mut self,
+            _info: &SessionInfo,
             authorization_id: &str,
             authentication_id: &str,
             password: &str,
@@ -402,7 +2248,7 @@ mod tests {
             )
         }
 
-        fn auth_login(&mut self, username: &str, password: &str) -> Response {
+        fn auth_login(&mut self, _info: &SessionInfo, username: &str, password: &str) -> Response {
             ternary!(
                 username == "test" && password == "1234",
                 AUTH_OK,
@@ -450,7 +2296,7 @@ mod tests {
         let greeting = String::from_utf8(res.buffer().unwrap()).unwrap();
         assert_eq!(
             greeting,
-            "250-server offers extensions:\r\n250-8BITMIME\r\n250 AUTH PLAIN LOGIN\r\n".to_string()
+            "250-server offers extensions:\r\n250-8BITMIME\r\n250-PIPELINING\r\n250-CHUNKING\r\n250-BINARYMIME\r\n250-SMTPUTF8\r\n250-DSN\r\n250-ENHANCEDSTATUSCODES\r\n250-REQUIRETLS\r\n250 AUTH PLAIN LOGIN\r\n".to_string()
         )
     }
 
@@ -464,6 +2310,8 @@ mod tests {
         res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
         assert_eq!(res.code, 235);
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(session.info().auth_identity(), Some("test"));
+        assert!(session.info().is_tls());
     }
 
     #[test]
@@ -639,4 +2487,442 @@ mod tests {
         assert_eq!(res.code, 250);
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
     }
+
+    struct SaslHandler {}
+    impl Handler for SaslHandler {
+        fn auth_cram_md5_secret(&mut self, _info: &SessionInfo, username: &str) -> Option<String> {
+            ternary!(username == "test", Some("1234".to_string()), None)
+        }
+
+        fn auth_scram_sha256_credentials(
+            &mut self,
+            _info: &SessionInfo,
+            username: &str,
+        ) -> Option<ScramCredentials> {
+            ternary!(
+                username == "test",
+                Some(ScramCredentials::new("1234", b"salt", 4096)),
+                None
+            )
+        }
+    }
+
+    fn new_sasl_session() -> Session<SaslHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::Plain);
+        builder.enable_auth(AuthMechanism::CramMd5);
+        builder.enable_auth(AuthMechanism::ScramSha256);
+        builder.build(addr, SaslHandler {})
+    }
+
+    // The decoded data of a 334 challenge
+    fn challenge_data(res: &Response) -> String {
+        assert_eq!(res.code, 334);
+        let buf = res.buffer().unwrap();
+        let encoded = &buf[4..buf.len() - 2];
+        String::from_utf8(base64::decode(encoded).unwrap()).unwrap()
+    }
+
+    fn hmac<M: Mac + hmac::digest::KeyInit>(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = <M as Mac>::new_from_slice(key).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    #[test]
+    fn challenge_response_without_tls() {
+        let mut session = new_sasl_session();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 AUTH CRAM-MD5 SCRAM-SHA-256\r\n"));
+    }
+
+    #[test]
+    fn auth_cram_md5() {
+        let mut session = new_sasl_session();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth cram-md5\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::Auth);
+        let challenge = challenge_data(&res);
+        assert!(challenge.ends_with("@some.domain>"));
+        let digest: String = hmac::<Hmac<md5::Md5>>(b"1234", challenge.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let mut line = base64::encode(&format!("test {digest}"));
+        line.push_str("\r\n");
+        let res = session.process(line.as_bytes());
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn bad_auth_cram_md5() {
+        let mut session = new_sasl_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"auth cram-md5\r\n");
+        let res = session.process(b"dGVzdCBiOTEzYTYwMmM3ZWRhN2E0OTViNGU2ZTczMzRkMzg5MA==\r\n");
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    #[test]
+    fn auth_cancel() {
+        let mut session = new_sasl_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"auth cram-md5\r\n");
+        let res = session.process(b"*\r\n");
+        assert_eq!(res.code, 501);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    #[test]
+    fn auth_scram_sha256() {
+        let mut session = new_sasl_session();
+        session.process(b"ehlo a.domain\r\n");
+        let client_first_bare = "n=test,r=clientnonce";
+        let mut line = b"auth scram-sha-256 ".to_vec();
+        line.extend(base64::encode(&format!("n,,{client_first_bare}")).bytes());
+        line.extend(b"\r\n");
+        let res = session.process(&line);
+        assert_state!(session.fsm.current_state(), SmtpState::Auth);
+        let server_first = challenge_data(&res);
+        let nonce = server_first.split(',').next().unwrap();
+        assert!(nonce.starts_with("r=clientnonce"));
+        assert!(server_first.ends_with(",s=c2FsdA==,i=4096"));
+
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(b"1234", b"salt", 4096, &mut salted_password);
+        let client_key = hmac::<Hmac<Sha256>>(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let without_proof = format!("c=biws,{nonce}");
+        let auth_message = format!("{client_first_bare},{server_first},{without_proof}");
+        let client_signature = hmac::<Hmac<Sha256>>(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(k, s)| k ^ s)
+            .collect();
+        let client_final = format!("{without_proof},p={}", base64::encode(&proof));
+        let mut line = base64::encode(&client_final);
+        line.push_str("\r\n");
+        let res = session.process(line.as_bytes());
+        let server_key = hmac::<Hmac<Sha256>>(&salted_password, b"Server Key");
+        let server_signature = hmac::<Hmac<Sha256>>(&server_key, auth_message.as_bytes());
+        assert_eq!(
+            challenge_data(&res),
+            format!("v={}", base64::encode(&server_signature))
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Auth);
+        let res = session.process(b"\r\n");
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(session.info().auth_identity(), Some("test"));
+    }
+
+    #[test]
+    fn bad_auth_scram_sha256() {
+        let mut session = new_sasl_session();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth scram-sha-256\r\n");
+        assert_eq!(res, EMPTY_AUTH_CHALLENGE);
+        let res = session.process(b"biwsbj10ZXN0LHI9Y2xpZW50bm9uY2U=\r\n"); // n,,n=test,r=clientnonce
+        assert_eq!(res.code, 334);
+        let res = session.process(b"Yz1iaXdzLHI9Y2xpZW50bm9uY2UscD1BQUFB\r\n"); // c=biws,r=clientnonce,p=AAAA
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    #[test]
+    fn unknown_user_auth_scram_sha256() {
+        let server_first = || {
+            let mut session = new_sasl_session();
+            session.process(b"ehlo a.domain\r\n");
+            let line = format!(
+                "auth scram-sha-256 {}\r\n",
+                base64::encode("n,,n=kraken,r=nonce")
+            );
+            let res = session.process(line.as_bytes());
+            assert_eq!(res.code, 334);
+            let server_first = challenge_data(&res);
+            (session, server_first)
+        };
+        let (mut session, first) = server_first();
+        let (_, second) = server_first();
+        // The fake salt and iterations do not change between attempts
+        let salt = |msg: &str| msg.split_once(",s=").unwrap().1.to_string();
+        assert_eq!(salt(&first), salt(&second));
+        assert!(first.ends_with(",i=4096"));
+        let nonce = first.split(',').next().unwrap();
+        let client_final = format!("c=biws,{nonce},p={}", base64::encode(&[0u8; 32]));
+        let line = format!("{}\r\n", base64::encode(&client_final));
+        let res = session.process(line.as_bytes());
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    struct BearerHandler {}
+    impl Handler for BearerHandler {
+        fn auth_bearer(&mut self, _info: &SessionInfo, user: &str, token: &str) -> Response {
+            ternary!(
+                user == "test" && token == "vF9dft4qmTc2",
+                AUTH_OK,
+                INVALID_CREDENTIALS
+            )
+        }
+    }
+
+    fn new_bearer_session() -> Session<BearerHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::OAuthBearer);
+        builder.enable_auth(AuthMechanism::XOAuth2);
+        builder.enable_start_tls();
+        builder.build(addr, BearerHandler {})
+    }
+
+    fn auth_bearer_line(mechanism: &str, response: &str) -> Vec<u8> {
+        format!("auth {} {}\r\n", mechanism, base64::encode(response)).into_bytes()
+    }
+
+    #[test]
+    fn auth_bearer_requires_tls() {
+        let mut session = new_bearer_session();
+        session.process(b"ehlo a.domain\r\n");
+        let line = auth_bearer_line("xoauth2", "user=test\x01auth=Bearer vF9dft4qmTc2\x01\x01");
+        let res = session.process(&line);
+        assert_eq!(res.code, 503);
+    }
+
+    #[test]
+    fn auth_oauthbearer() {
+        let mut session = new_bearer_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 AUTH OAUTHBEARER XOAUTH2\r\n"));
+        let line = auth_bearer_line(
+            "oauthbearer",
+            "n,a=test,\x01host=some.domain\x01port=587\x01auth=Bearer vF9dft4qmTc2\x01\x01",
+        );
+        let res = session.process(&line);
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn auth_xoauth2() {
+        let mut session = new_bearer_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth xoauth2\r\n");
+        assert_eq!(res, EMPTY_AUTH_CHALLENGE);
+        let mut line = base64::encode("user=test\x01auth=Bearer vF9dft4qmTc2\x01\x01");
+        line.push_str("\r\n");
+        let res = session.process(line.as_bytes());
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn bad_auth_oauthbearer() {
+        let mut session = new_bearer_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        session.process(b"ehlo a.domain\r\n");
+        let line = auth_bearer_line("oauthbearer", "n,a=test,\x01auth=Bearer expired\x01\x01");
+        let res = session.process(&line);
+        assert_eq!(
+            challenge_data(&res),
+            r#"{"status":"invalid_token","schemes":"bearer"}"#
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Auth);
+        let res = session.process(b"AQ==\r\n");
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    // A mechanism that asks for a name and then a token
+    struct TokenMechanism(&'static str);
+    struct TokenExchange {
+        name: Option<String>,
+    }
+
+    impl SaslMechanism for TokenMechanism {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn requires_tls(&self) -> bool {
+            false
+        }
+
+        fn exchange(&self) -> Box<dyn SaslExchange> {
+            Box::new(TokenExchange { name: None })
+        }
+    }
+
+    impl SaslExchange for TokenExchange {
+        fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+            match (&self.name, response) {
+                (None, Some(name)) => {
+                    self.name = Some(String::from_utf8_lossy(name).to_string());
+                    SaslStep::Challenge(b"token?".to_vec())
+                }
+                (None, None) => SaslStep::Challenge(b"name?".to_vec()),
+                (Some(name), Some(b"1234")) => SaslStep::Success(name.clone()),
+                _ => SaslStep::Failure(INVALID_CREDENTIALS),
+            }
+        }
+    }
+
+    struct SaslIdentityHandler(&'static str, Option<String>);
+    impl Handler for SaslIdentityHandler {
+        fn auth_sasl(&mut self, _info: &SessionInfo, mechanism: &str, identity: &str) -> Response {
+            assert_eq!(mechanism, self.0);
+            self.1 = Some(identity.to_string());
+            AUTH_OK
+        }
+    }
+
+    fn new_mechanism_session(name: &'static str) -> Session<SaslIdentityHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_sasl_mechanism(TokenMechanism(name));
+        builder.build(addr, SaslIdentityHandler(name, None))
+    }
+
+    fn new_token_session() -> Session<SaslIdentityHandler> {
+        new_mechanism_session("X-TOKEN")
+    }
+
+    #[test]
+    fn auth_registered_mechanism() {
+        let mut session = new_token_session();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 AUTH X-TOKEN\r\n"));
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        let res = session.process(b"auth x-token\r\n");
+        assert_eq!(challenge_data(&res), "name?");
+        let res = session.process(b"dGVzdA==\r\n"); // "test"
+        assert_eq!(challenge_data(&res), "token?");
+        assert_state!(session.fsm.current_state(), SmtpState::Auth);
+        let res = session.process(b"MTIzNA==\r\n"); // "1234"
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(session.handler.1, Some("test".to_string()));
+    }
+
+    #[test]
+    fn auth_registered_mechanism_with_builtin_prefix() {
+        let mut session = new_mechanism_session("SCRAM-SHA-256-PLUS");
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 AUTH SCRAM-SHA-256-PLUS\r\n"));
+        let res = session.process(b"auth scram-sha-256-plus dGVzdA==\r\n");
+        assert_eq!(challenge_data(&res), "token?");
+        let res = session.process(b"MTIzNA==\r\n"); // "1234"
+        assert_eq!(res.code, 235);
+        assert_eq!(session.handler.1, Some("test".to_string()));
+    }
+
+    #[test]
+    fn bad_auth_registered_mechanism() {
+        let mut session = new_token_session();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth x-token dGVzdA==\r\n");
+        assert_eq!(challenge_data(&res), "token?");
+        let res = session.process(b"NDMyMQ==\r\n"); // "4321"
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        assert_eq!(session.handler.1, None);
+    }
+
+    #[test]
+    fn auth_unknown_mechanism() {
+        let mut session = new_token_session();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth x-unknown\r\n");
+        assert_eq!(res.code, 504);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    struct ExternalHandler {}
+    impl Handler for ExternalHandler {
+        fn auth_external(
+            &mut self,
+            _info: &SessionInfo,
+            authorization_id: &str,
+            certificates: &[Vec<u8>],
+        ) -> Result<String, Response> {
+            if certificates == [b"relay-cert".to_vec()]
+                && (authorization_id.is_empty() || authorization_id == "relay")
+            {
+                Ok("relay".to_string())
+            } else {
+                Err(INVALID_CREDENTIALS)
+            }
+        }
+    }
+
+    fn new_external_session(certificates: Vec<Vec<u8>>) -> Session<ExternalHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::External);
+        builder.enable_start_tls();
+        let mut session = builder.build(addr, ExternalHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_active_with_peer_certificates(certificates);
+        session
+    }
+
+    #[test]
+    fn auth_external() {
+        let mut session = new_external_session(vec![b"relay-cert".to_vec()]);
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 AUTH EXTERNAL\r\n"));
+        let res = session.process(b"auth external =\r\n");
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(session.info().auth_identity(), Some("relay"));
+    }
+
+    #[test]
+    fn auth_external_challenge() {
+        let mut session = new_external_session(vec![b"relay-cert".to_vec()]);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth external\r\n");
+        assert_eq!(res, EMPTY_AUTH_CHALLENGE);
+        let res = session.process(b"cmVsYXk=\r\n"); // "relay"
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn bad_auth_external() {
+        let mut session = new_external_session(vec![b"relay-cert".to_vec()]);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth external YWRtaW4=\r\n"); // "admin"
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    #[test]
+    fn auth_external_without_certificate() {
+        let mut session = new_external_session(Vec::new());
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(!ehlo.contains("AUTH"));
+        let res = session.process(b"auth external =\r\n");
+        assert_eq!(res.code, 503);
+    }
 }
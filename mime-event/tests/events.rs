@@ -177,23 +177,23 @@ fn header(header: Header) -> Event {
     Event::Header(header)
 }
 
-fn from(from: &str) -> Event {
+fn from(from: &str) -> Event<'_> {
     header(Header::From(from.as_bytes()))
 }
 
-fn to(to: &str) -> Event {
+fn to(to: &str) -> Event<'_> {
     header(Header::To(to.as_bytes()))
 }
 
-fn message_id(message_id: &str) -> Event {
+fn message_id(message_id: &str) -> Event<'_> {
     header(Header::MessageId(message_id.as_bytes()))
 }
 
-fn subject(subject: &str) -> Event {
+fn subject(subject: &str) -> Event<'_> {
     header(Header::Subject(subject.as_bytes()))
 }
 
-fn date(date: &str) -> Event {
+fn date(date: &str) -> Event<'_> {
     header(Header::Date(date.as_bytes()))
 }
 
@@ -204,7 +204,7 @@ fn content_type<'a>(mime: &'a str, param_key: &'a str, param_value: &str) -> Eve
     })
 }
 
-fn body(block: &str) -> Event {
+fn body(block: &str) -> Event<'_> {
     Event::Body(block.as_bytes())
 }
 